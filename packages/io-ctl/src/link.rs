@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use io_service::config::MqttConfig;
+use io_service::protocol::Request;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, TlsConfiguration};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+// Marks what io-ctl sends, so its own commands are skipped when they come
+// back on a subscribed topic.
+pub const SOURCE: &str = "io-ctl";
+
+// JSON from the service; io-ctl's own commands are dropped.
+fn parse(payload: &[u8]) -> Option<Value> {
+    serde_json::from_slice::<Value>(payload).ok().filter(|value| value["source"] != SOURCE)
+}
+
+// A running io-service, reached over its local socket or the broker.
+pub enum Link {
+    Socket {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        write: OwnedWriteHalf,
+    },
+    Mqtt {
+        client: AsyncClient,
+        eventloop: Box<EventLoop>,
+        // where commands are published, must match topics.subscribe
+        topic: String,
+        // arrived while waiting for a PUBACK
+        early: VecDeque<(String, Value)>,
+    },
+}
+
+impl Link {
+    pub async fn socket(path: &Path) -> Result<Link, String> {
+        let stream = UnixStream::connect(path).await.map_err(|e| format!("socket {:?}: {}", path, e))?;
+        let (read, write) = stream.into_split();
+        Ok(Link::Socket { lines: BufReader::new(read).lines(), write })
+    }
+
+    // Connects and subscribes to `subscribe` before returning, so no answer
+    // can slip by.
+    pub async fn mqtt(config: &MqttConfig, password: String, topic: String, subscribe: &[String]) -> Result<Link, String> {
+        let client_id = format!("io-ctl-{}", std::process::id());
+        let mut options = MqttOptions::new(client_id, config.host.clone(), config.port);
+        if !config.username.is_empty() {
+            options.set_credentials(config.username.clone(), password);
+        }
+        if config.tls.enabled {
+            let tls = io_service::transport::tls::client_config(&config.tls)?;
+            options.set_transport(rumqttc::Transport::tls_with_config(TlsConfiguration::Rustls(tls)));
+        }
+        options.set_keep_alive(std::time::Duration::from_secs(config.keep_alive_s.max(5)));
+
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        for topic in subscribe {
+            client.subscribe(topic.clone(), QoS::AtMostOnce).await.map_err(|e| e.to_string())?;
+        }
+        let mut acked = 0;
+        while acked < subscribe.len() {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::SubAck(_))) => acked += 1,
+                Ok(_) => {}
+                Err(e) => return Err(format!("mqtt {}:{}: {}", config.host, config.port, e)),
+            }
+        }
+        Ok(Link::Mqtt { client, eventloop: Box::new(eventloop), topic, early: VecDeque::new() })
+    }
+
+    pub fn is_socket(&self) -> bool {
+        matches!(self, Link::Socket { .. })
+    }
+
+    pub async fn send(&mut self, request: &Request) -> Result<(), String> {
+        match self {
+            Link::Socket { write, .. } => {
+                let line = format!("{}\n", request);
+                write.write_all(line.as_bytes()).await.map_err(|e| format!("socket: {}", e))
+            }
+            // returns once the broker has it
+            Link::Mqtt { client, eventloop, topic, early } => {
+                client
+                    .publish(topic.clone(), QoS::AtLeastOnce, false, request.to_string())
+                    .await
+                    .map_err(|e| format!("mqtt: {}", e))?;
+                loop {
+                    match eventloop.poll().await {
+                        Ok(Event::Incoming(Packet::PubAck(_))) => return Ok(()),
+                        Ok(Event::Incoming(Packet::Publish(publish))) => {
+                            if let Some(value) = parse(&publish.payload) {
+                                early.push_back((publish.topic, value));
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => return Err(format!("mqtt: {}", e)),
+                    }
+                }
+            }
+        }
+    }
+
+    // Next JSON message from the service, with the topic it came on (empty
+    // for the socket).
+    pub async fn next(&mut self) -> Result<(String, Value), String> {
+        match self {
+            Link::Socket { lines, .. } => match lines.next_line().await {
+                Ok(Some(line)) => match serde_json::from_str(&line) {
+                    Ok(value) => Ok((String::new(), value)),
+                    Err(e) => Err(format!("socket: bad reply {:?}: {}", line, e)),
+                },
+                Ok(None) => Err("socket: closed by io-service".to_string()),
+                Err(e) => Err(format!("socket: {}", e)),
+            },
+            Link::Mqtt { eventloop, early, .. } => loop {
+                if let Some(message) = early.pop_front() {
+                    return Ok(message);
+                }
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        if let Some(value) = parse(&publish.payload) {
+                            return Ok((publish.topic, value));
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => return Err(format!("mqtt: {}", e)),
+                }
+            },
+        }
+    }
+}
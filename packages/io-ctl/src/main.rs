@@ -0,0 +1,344 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use io_service::config::Config;
+use io_service::identity::{parse_sources, Identity};
+use io_service::json::{get_value, led_command_value, relay_command_value};
+use io_service::protocol::{Request, Status};
+use link::{Link, SOURCE};
+use rand::Rng;
+use serde_json::Value;
+use std::path::PathBuf;
+use tokio::time::{timeout, Duration, Instant};
+pub mod link;
+
+/*
+io-ctl relay set 2 on
+io-ctl relay list
+io-ctl led blink 1 --freq 500
+io-ctl watch
+io-ctl --mqtt sync
+
+the local socket is used unless --mqtt is given; broker, topics, socket path
+and device id come from the io-service config file
+*/
+
+const DEFAULT_CONFIG: &str = "/etc/io-service/io-service.toml";
+
+#[derive(Debug, Parser)]
+#[command(author, version, about = "Operator client for a running io-service", long_about = None)]
+struct Args {
+    // io-service config to take the broker, topics, socket and id from
+    #[clap(short, long, env = "IO_CTL_CONFIG")]
+    config: Option<PathBuf>,
+
+    // go through the MQTT broker instead of the local socket
+    #[clap(long)]
+    mqtt: bool,
+
+    #[clap(long, env = "IO_CTL_SOCKET")]
+    socket: Option<PathBuf>,
+
+    #[clap(long, env = "IO_CTL_MQTT_HOST")]
+    mqtt_host: Option<String>,
+
+    #[clap(long, env = "IO_CTL_MQTT_PORT")]
+    mqtt_port: Option<u16>,
+
+    // topic commands are published on; by default topics.subscribe with the
+    // wildcard replaced by "control"
+    #[clap(long, env = "IO_CTL_TOPIC")]
+    topic: Option<String>,
+
+    // device id used in the relay hashes
+    #[clap(long, env = "IO_CTL_ID")]
+    id: Option<String>,
+
+    #[clap(long, default_value_t = 5000)]
+    timeout_ms: u64,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    #[command(subcommand)]
+    Relay(RelayCommand),
+    // set a led on, off or blinking
+    Led {
+        mode: LedArg,
+        led: usize,
+        // blink period in ms
+        #[clap(long)]
+        freq: Option<u16>,
+    },
+    // stream relay, button and temperature events until interrupted
+    Watch,
+    // send a "get" and print what comes back
+    Sync,
+    // cpu temperature
+    Temp,
+}
+
+#[derive(Debug, Subcommand)]
+enum RelayCommand {
+    Set { relay: usize, state: OnOff },
+    List,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OnOff {
+    On,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LedArg {
+    On,
+    Off,
+    Blink,
+}
+
+impl LedArg {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LedArg::On => "on",
+            LedArg::Off => "off",
+            LedArg::Blink => "blink",
+        }
+    }
+}
+
+fn reqid() -> String {
+    rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect()
+}
+
+// "component/io/+" -> "component/io/control"
+fn command_topic(subscribe: &str) -> String {
+    subscribe.replace(['+', '#'], "control")
+}
+
+// (relay, on) from a status message; the relay index is the last part of
+// the io-<id>-<n> hash.
+fn relays(status: &Value) -> Vec<(usize, bool)> {
+    let status: Status = match serde_json::from_value(status.clone()) {
+        Ok(status) => status,
+        Err(_) => return Vec::new(),
+    };
+    status.objects.iter()
+        .flat_map(|object| &object.data)
+        .filter_map(|item| Some((item.hash.rsplit('-').next()?.parse().ok()?, item.states.on_off.on)))
+        .collect()
+}
+
+fn error_of(reply: &Value) -> Option<String> {
+    if reply["cmd"] == "error" {
+        return Some(reply["error"].as_str().unwrap_or("unknown error").to_string());
+    }
+    reply["objects"][0]["data"][0]["error"].as_str().map(String::from)
+}
+
+struct Ctl {
+    link: Link,
+    config: Config,
+    id: Option<String>,
+    timeout: Duration,
+}
+
+impl Ctl {
+    // First message within the timeout that `matches`.
+    async fn wait(&mut self, what: &str, matches: impl Fn(&str, &Value) -> bool) -> Result<Value, String> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match timeout(left, self.link.next()).await {
+                Ok(Ok((topic, value))) if matches(&topic, &value) => return Ok(value),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(format!("no {} within {} ms", what, self.timeout.as_millis())),
+            }
+        }
+    }
+
+    async fn request(&mut self, request: Request) -> Result<Value, String> {
+        self.link.send(&request).await?;
+        let reply = self.wait("reply", |_, reply| reply["reqid"] == request.reqid.as_str()).await?;
+        match error_of(&reply) {
+            Some(e) => Err(e),
+            None => Ok(reply),
+        }
+    }
+
+    // The relay status of the whole device. Over MQTT only the Ai firmware
+    // answers a "get".
+    async fn status(&mut self) -> Result<Value, String> {
+        let request = get_value("devices", &reqid(), SOURCE);
+        if self.link.is_socket() {
+            return self.request(request).await;
+        }
+        self.link.send(&request).await?;
+        let status = self.config.mqtt.topics.status.clone();
+        self.wait("status (only Ai devices answer get over MQTT)", |topic, value| {
+            topic == status && value["cmd"] == "status" && value["objects"][0]["type"] == "devices"
+        })
+        .await
+    }
+
+    async fn id(&mut self) -> Result<String, String> {
+        if let Some(id) = &self.id {
+            return Ok(id.clone());
+        }
+        if self.link.is_socket() {
+            let status = self.status().await?;
+            let hash = status["objects"][0]["data"][0]["hash"].as_str().unwrap_or_default();
+            if let Some(id) = hash.split('-').nth(1) {
+                return Ok(id.to_string());
+            }
+            return Err("io-service has no relays".to_string());
+        }
+        // same box as io-service: derive it the same way
+        let identity = &self.config.identity;
+        let sources = parse_sources(&identity.sources)?;
+        let (_, id) = Identity::new(&identity.root, &identity.interface, sources).resolve()?;
+        Ok(id)
+    }
+
+    async fn run(&mut self, command: Command) -> Result<(), String> {
+        match command {
+            Command::Relay(RelayCommand::Set { relay, state }) => {
+                let id = self.id().await?;
+                let on = matches!(state, OnOff::On);
+                let reply = self.request(relay_command_value(&id, relay, on, &reqid(), SOURCE)).await?;
+                for (relay, on) in relays(&reply) {
+                    println!("relay {}: {}", relay, if on { "on" } else { "off" });
+                }
+            }
+            Command::Relay(RelayCommand::List) | Command::Sync => {
+                let sync = matches!(command, Command::Sync);
+                let status = self.status().await?;
+                if sync {
+                    println!("{}", serde_json::to_string_pretty(&status).unwrap_or_default());
+                }
+                else {
+                    for (relay, on) in relays(&status) {
+                        println!("relay {}: {}", relay, if on { "on" } else { "off" });
+                    }
+                }
+            }
+            Command::Led { mode, led, freq } => {
+                let request = led_command_value(led, mode.as_str(), freq, &reqid(), SOURCE);
+                if self.link.is_socket() {
+                    self.request(request).await?;
+                }
+                else {
+                    // nothing answers a led command over MQTT
+                    self.link.send(&request).await?;
+                }
+                println!("led {}: {}", led, mode.as_str());
+            }
+            Command::Temp => {
+                if !self.link.is_socket() {
+                    return Err("io-service does not publish the temperature over MQTT, use the socket".to_string());
+                }
+                let reply = self.request(get_value("temperature", &reqid(), SOURCE)).await?;
+                println!("{} °C", reply["objects"][0]["data"][0]["celsius"]);
+            }
+            Command::Watch => {
+                if self.link.is_socket() {
+                    let subscribe = Request { cmd: "subscribe".to_string(), reqid: reqid(), source: SOURCE.to_string(), ..Default::default() };
+                    self.request(subscribe).await?;
+                }
+                loop {
+                    let (topic, value) = self.link.next().await?;
+                    match topic.is_empty() {
+                        true => println!("{}", value),
+                        false => println!("{}: {}", topic, value),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn connect(args: &Args, config: &Config) -> Result<Link, String> {
+    if !args.mqtt {
+        let path = args.socket.clone().unwrap_or_else(|| PathBuf::from(&config.socket.path));
+        return Link::socket(&path).await;
+    }
+    let mut mqtt = config.mqtt.clone();
+    if let Some(host) = &args.mqtt_host {
+        mqtt.host = host.clone();
+    }
+    if let Some(port) = args.mqtt_port {
+        mqtt.port = port;
+    }
+    let password = match mqtt.password_file.is_empty() {
+        true => mqtt.password.clone(),
+        false => std::fs::read_to_string(&mqtt.password_file)
+            .map_err(|e| format!("mqtt.password_file = {:?}: {}", mqtt.password_file, e))?
+            .trim_end_matches(['\r', '\n'])
+            .to_string(),
+    };
+    let topic = args.topic.clone().unwrap_or_else(|| command_topic(&mqtt.topics.subscribe));
+    let topics = &mqtt.topics;
+    let subscribe: Vec<String> = match args.command {
+        Command::Watch => vec![topics.status.clone(), topics.event.clone(), topics.config.clone(), topics.presence.clone()],
+        _ => vec![topics.status.clone()],
+    };
+    let subscribe: Vec<String> = subscribe.into_iter().filter(|t| !t.is_empty()).collect();
+    Link::mqtt(&mqtt, password, topic, &subscribe).await
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    let config = match &args.config {
+        Some(path) => Config::load(path),
+        None if std::path::Path::new(DEFAULT_CONFIG).exists() => Config::load(DEFAULT_CONFIG.as_ref()),
+        None => Ok(Config::default()),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("config: {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let link = match connect(&args, &config).await {
+        Ok(link) => link,
+        Err(e) => {
+            eprintln!("io-ctl: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let id = args.id.clone().or_else(|| Some(config.identity.id_mac.clone()).filter(|id| !id.is_empty()));
+    let mut ctl = Ctl { link, config, id, timeout: Duration::from_millis(args.timeout_ms) };
+    if let Err(e) = ctl.run(args.command).await {
+        eprintln!("io-ctl: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status_parsing() {
+        assert_eq!(command_topic("component/io/+"), "component/io/control");
+
+        let request = Request { reqid: "r1".to_string(), ..Default::default() };
+        let status = io_service::json::status_value(&request, &[(false, "io-AB12-0".to_string()), (true, "io-AB12-1".to_string())]);
+        let status = serde_json::to_value(status).unwrap();
+        assert_eq!(relays(&status), vec![(0, false), (1, true)]);
+        assert_eq!(error_of(&status), None);
+        let busy = serde_json::to_value(io_service::json::reply_value("r1", Some("busy"))).unwrap();
+        assert_eq!(error_of(&busy), Some("busy".to_string()));
+    }
+}
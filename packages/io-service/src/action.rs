@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use dbus::nonblock;
+use dbus_tokio::connection;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use crate::error::OtaErr;
+
+// How a named button action (see GestureMap) is carried out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActionKind {
+    // publish {"cmd": <action>} on the topic, handled by SystemIntergration
+    Mqtt { topic: String },
+    // run a local program
+    Command { program: String, args: Vec<String> },
+    // call a method without arguments on the system bus
+    Dbus { destination: String, path: String, interface: String, method: String },
+}
+
+impl FromStr for ActionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s.split_once(':').unwrap_or((s, ""));
+        let mut words = rest.split_whitespace().map(String::from);
+        match kind {
+            "mqtt" if !rest.is_empty() => Ok(ActionKind::Mqtt { topic: rest.to_string() }),
+            "exec" => {
+                let program = words.next().ok_or_else(|| format!("missing program in {:?}", s))?;
+                Ok(ActionKind::Command { program, args: words.collect() })
+            }
+            "dbus" => {
+                let fields: Vec<String> = words.collect();
+                match <[String; 4]>::try_from(fields) {
+                    Ok([destination, path, interface, method]) => Ok(ActionKind::Dbus { destination, path, interface, method }),
+                    Err(_) => Err(format!("dbus action {:?} needs destination path interface method", s)),
+                }
+            }
+            _ => Err(format!("unknown action {:?}, expected mqtt:TOPIC, exec:PROGRAM or dbus:DEST PATH IFACE METHOD", s)),
+        }
+    }
+}
+
+// Action name -> kind. Names without an entry are published over MQTT.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionMap {
+    pub default_topic: String,
+    actions: HashMap<String, ActionKind>,
+}
+
+impl Default for ActionMap {
+    fn default() -> Self {
+        ActionMap {
+            default_topic: "component/io/command".to_string(),
+            actions: HashMap::new(),
+        }
+    }
+}
+
+impl ActionMap {
+    pub fn kind(&self, action: &str) -> ActionKind {
+        self.actions.get(action).cloned().unwrap_or(ActionKind::Mqtt { topic: self.default_topic.clone() })
+    }
+}
+
+// "reboot=exec:/sbin/reboot;pair_zigbee=dbus:org.lumi.Zigbee /org/lumi/Zigbee org.lumi.Zigbee PermitJoin"
+impl FromStr for ActionMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = ActionMap::default();
+        for item in s.split(';').map(str::trim).filter(|i| !i.is_empty()) {
+            let (name, kind) = item.split_once('=').ok_or_else(|| format!("action {:?} is not name=kind", item))?;
+            map.actions.insert(name.trim().to_string(), kind.trim().parse()?);
+        }
+        Ok(map)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ActionOut {
+    Done { action: String, result: Result<(), OtaErr> },
+}
+
+// Runs command and D-Bus actions off the main loop and reports when done.
+pub struct ActionDriver {
+    pub tx: mpsc::Sender<ActionOut>,
+    pub rx: mpsc::Receiver<ActionOut>,
+}
+
+async fn run_command(program: &str, args: &[String]) -> Result<(), OtaErr> {
+    let status = Command::new(program).args(args).status().await.map_err(|e| {
+        log::error!("action {}: {}", program, e);
+        OtaErr::ActionErr
+    })?;
+    if !status.success() {
+        log::error!("action {} exited with {}", program, status);
+        return Err(OtaErr::ActionErr);
+    }
+    Ok(())
+}
+
+async fn call_dbus(destination: &str, path: &str, interface: &str, method: &str) -> Result<(), OtaErr> {
+    let (resource, conn) = connection::new_system_sync().map_err(|e| {
+        log::error!("action dbus: {}", e);
+        OtaErr::ActionErr
+    })?;
+    let bus = tokio::spawn(async move {
+        let err = resource.await;
+        log::warn!("action dbus connection closed: {}", err);
+    });
+
+    let proxy = nonblock::Proxy::new(destination, path, Duration::from_secs(5), conn);
+    let result: Result<(), dbus::Error> = proxy.method_call(interface, method, ()).await;
+    bus.abort();
+    result.map_err(|e| {
+        log::error!("action {}.{}: {}", interface, method, e);
+        OtaErr::ActionErr
+    })
+}
+
+impl ActionDriver {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel::<ActionOut>(5);
+        ActionDriver { tx, rx }
+    }
+
+    pub fn send(&mut self, action: String, kind: ActionKind) {
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let result = match &kind {
+                ActionKind::Command { program, args } => run_command(program, args).await,
+                ActionKind::Dbus { destination, path, interface, method } => call_dbus(destination, path, interface, method).await,
+                ActionKind::Mqtt { .. } => Ok(()),
+            };
+            let _ = tx.send(ActionOut::Done { action, result }).await;
+        });
+    }
+
+    pub async fn recv(&mut self) -> ActionOut {
+        self.rx.recv().await.unwrap()
+    }
+}
+
+impl Default for ActionDriver {
+    fn default() -> Self {
+        ActionDriver::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_action_map() {
+        let map = "reboot=exec:/sbin/reboot -f; pair=dbus:org.lumi.Zigbee /org/lumi/Zigbee org.lumi.Zigbee PermitJoin; wifi=mqtt:component/wifi/command"
+            .parse::<ActionMap>()
+            .unwrap();
+        assert_eq!(map.kind("reboot"), ActionKind::Command { program: "/sbin/reboot".to_string(), args: vec!["-f".to_string()] });
+        assert_eq!(map.kind("pair"), ActionKind::Dbus {
+            destination: "org.lumi.Zigbee".to_string(),
+            path: "/org/lumi/Zigbee".to_string(),
+            interface: "org.lumi.Zigbee".to_string(),
+            method: "PermitJoin".to_string(),
+        });
+        assert_eq!(map.kind("wifi"), ActionKind::Mqtt { topic: "component/wifi/command".to_string() });
+        assert_eq!(map.kind("factory_reset"), ActionKind::Mqtt { topic: "component/io/command".to_string() });
+
+        assert!("x=dbus:org.lumi.Zigbee".parse::<ActionMap>().is_err());
+        assert!("x=ssh:host".parse::<ActionMap>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_command_result() {
+        let mut driver = ActionDriver::new();
+        driver.send("ok".to_string(), "exec:true".parse().unwrap());
+        let ActionOut::Done { result, .. } = driver.recv().await;
+        assert_eq!(result, Ok(()));
+
+        driver.send("fail".to_string(), "exec:false".parse().unwrap());
+        let ActionOut::Done { action, result } = driver.recv().await;
+        assert_eq!(action, "fail");
+        assert_eq!(result, Err(OtaErr::ActionErr));
+    }
+}
@@ -0,0 +1,813 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use crate::action::ActionMap;
+use crate::fan::FanCurve;
+use crate::gesture::{GestureConfig, GestureMap};
+use crate::gpio::{GpioBackend, Polarity};
+use crate::gpio::chardev::ChardevBackend;
+use crate::gpio::line::{Line, PinSpec};
+use crate::gpio::memory::MemoryBackend;
+use crate::gpio::sysfs::SysfsBackend;
+use crate::thermal::{Aggregation, ThermalSource};
+use crate::identity::{self, Identity};
+use crate::interlock::Interlocks;
+use crate::relay_state::PowerOn;
+use crate::transport::MessageClass;
+
+// Loaded from the TOML file, then overridden by CLI args and env vars (see
+// main.rs). Values that have their own syntax (pin lists, fan curve, gesture
+// and action maps) are kept as strings here and parsed by `validate`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // "Ai" or "Hc"
+    pub device: String,
+    // keepalive and temperature check period
+    pub keepalive_s: u64,
+    pub identity: IdentityConfig,
+    pub mqtt: MqttConfig,
+    pub gpio: GpioConfig,
+    pub button: ButtonConfig,
+    pub fan: FanConfig,
+    pub thermal: ThermalConfig,
+    pub dbus: DbusConfig,
+    pub socket: SocketConfig,
+    pub http: HttpConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    // fixed id, takes precedence over `sources` when set
+    pub id_mac: String,
+    // tried in order: interface, machine-id, serial
+    pub sources: String,
+    // interface whose MAC is used by the interface source
+    pub interface: String,
+    // prefix for /sys, /etc and /proc
+    pub root: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    // no credentials are sent when empty
+    pub username: String,
+    pub password: String,
+    // file holding the password, used when `password` is empty
+    pub password_file: String,
+    pub keep_alive_s: u64,
+    // reconnect backoff, doubled on every failed attempt up to the max
+    pub reconnect_min_ms: u64,
+    pub reconnect_max_ms: u64,
+    // messages kept while offline, the oldest are dropped first
+    pub queue_len: usize,
+    // QoS 1/2 publishes without an ack after this long are reported
+    pub ack_timeout_ms: u64,
+    pub publish: PublishConfig,
+    pub tls: TlsConfig,
+    pub topics: Topics,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PublishPolicy {
+    pub qos: u8,
+    pub retain: bool,
+}
+
+// QoS and retain per outbound message class.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PublishConfig {
+    pub config: PublishPolicy,
+    pub status: PublishPolicy,
+    pub keepalive: PublishPolicy,
+    pub event: PublishPolicy,
+    pub command: PublishPolicy,
+    pub error: PublishPolicy,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        PublishConfig {
+            config: PublishPolicy { qos: 1, retain: true },
+            status: PublishPolicy { qos: 1, retain: true },
+            keepalive: PublishPolicy { qos: 0, retain: false },
+            event: PublishPolicy { qos: 1, retain: false },
+            command: PublishPolicy { qos: 1, retain: false },
+            error: PublishPolicy { qos: 1, retain: false },
+        }
+    }
+}
+
+impl PublishConfig {
+    pub fn policy(&self, class: MessageClass) -> PublishPolicy {
+        match class {
+            MessageClass::Config => self.config,
+            MessageClass::Status => self.status,
+            MessageClass::KeepAlive => self.keepalive,
+            MessageClass::Event => self.event,
+            MessageClass::Command => self.command,
+            MessageClass::Error => self.error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    // PEM CA bundle, empty uses the system certificates
+    pub ca_file: String,
+    // PEM client certificate chain and key for mutual auth
+    pub cert_file: String,
+    pub key_file: String,
+    pub alpn: Vec<String>,
+    // name the broker certificate is checked against, default the host
+    pub server_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Topics {
+    pub subscribe: String,
+    pub status: String,
+    pub config: String,
+    pub keepalive: String,
+    pub command: String,
+    pub event: String,
+    // retained online/offline state, also the last will; empty disables it
+    pub presence: String,
+    // replies to commands that were rejected; empty disables them
+    pub error: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpioConfig {
+    // sysfs, chardev or memory
+    pub backend: String,
+    // comma separated pins, see gpio/line.rs for the syntax
+    pub leds: String,
+    pub ios: String,
+    // per relay "high" or "low", in the order of ios; missing ones are low
+    pub relay_polarity: String,
+    // per relay last, off, on or cloud, in the order of ios; missing ones
+    // are last
+    pub relay_power_on: String,
+    // relay states kept across restarts; empty disables it, "last" then
+    // starts off
+    pub relay_state_file: String,
+    // relays that must not be on together, as ';'-separated RELAYS:MODE with
+    // the relays joined by '+'; modes are exclusive, forbid and dead-time=MS,
+    // e.g. "0+1:exclusive;2+3:dead-time=500"
+    pub relay_interlock: String,
+    pub fans: String,
+    // index in leds that shows the broker connection: on when connected,
+    // blinking while offline
+    pub network_led: Option<u64>,
+    // blink period of the hold progress, one more LED per period
+    pub time_blink_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ButtonConfig {
+    pub pin: String,
+    pub debounce_ms: u64,
+    pub long_press_ms: u64,
+    pub double_click_ms: u64,
+    // gesture=action pairs; gestures are short, double, long and holdN
+    pub gestures: String,
+    // ';'-separated name=mqtt:TOPIC|exec:PROGRAM ARGS|dbus:DEST PATH IFACE METHOD
+    pub actions: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FanConfig {
+    // levels as "temp:pattern", one pattern digit per fan pin
+    pub curve: String,
+    pub hysteresis: f32,
+    pub dwell_ms: u64,
+    pub fail_limit: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThermalConfig {
+    pub root: String,
+    // zone types to read, comma separated; empty reads every zone
+    pub zones: String,
+    // max or avg
+    pub policy: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbusConfig {
+    pub enabled: bool,
+    // system or session
+    pub bus: String,
+    // well-known name to own
+    pub name: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            device: "Hc".to_string(),
+            keepalive_s: 40,
+            identity: IdentityConfig::default(),
+            mqtt: MqttConfig::default(),
+            gpio: GpioConfig::default(),
+            button: ButtonConfig::default(),
+            fan: FanConfig::default(),
+            thermal: ThermalConfig::default(),
+            dbus: DbusConfig::default(),
+            socket: SocketConfig::default(),
+            http: HttpConfig::default(),
+        }
+    }
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        IdentityConfig {
+            id_mac: String::new(),
+            sources: "interface,machine-id,serial".to_string(),
+            interface: "eth0".to_string(),
+            root: "/".to_string(),
+        }
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: true,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "io_service".to_string(),
+            username: String::new(),
+            password: String::new(),
+            password_file: String::new(),
+            keep_alive_s: 5,
+            reconnect_min_ms: 500,
+            reconnect_max_ms: 30_000,
+            queue_len: 50,
+            ack_timeout_ms: 10_000,
+            publish: PublishConfig::default(),
+            tls: TlsConfig::default(),
+            topics: Topics::default(),
+        }
+    }
+}
+
+impl Default for Topics {
+    fn default() -> Self {
+        Topics {
+            subscribe: "component/io/+".to_string(),
+            status: "component/io/status".to_string(),
+            config: "component/io/config".to_string(),
+            keepalive: "component/keepalive/io-manager".to_string(),
+            command: "component/io/command".to_string(),
+            event: "component/io/event".to_string(),
+            presence: "component/presence/io-manager".to_string(),
+            error: "component/io/error".to_string(),
+        }
+    }
+}
+
+impl Default for GpioConfig {
+    fn default() -> Self {
+        GpioConfig {
+            backend: "sysfs".to_string(),
+            leds: String::new(),
+            ios: String::new(),
+            relay_polarity: String::new(),
+            relay_power_on: String::new(),
+            relay_state_file: "/var/lib/io-service/relays.json".to_string(),
+            relay_interlock: String::new(),
+            fans: String::new(),
+            network_led: None,
+            time_blink_ms: 1000,
+        }
+    }
+}
+
+impl Default for ButtonConfig {
+    fn default() -> Self {
+        ButtonConfig {
+            pin: "0".to_string(),
+            debounce_ms: 30,
+            long_press_ms: 1000,
+            double_click_ms: 300,
+            gestures: "double=pairing_mode,long=reboot,hold4=factory_reset".to_string(),
+            actions: String::new(),
+        }
+    }
+}
+
+impl Default for FanConfig {
+    fn default() -> Self {
+        FanConfig {
+            curve: "0:00,52:01,62:10".to_string(),
+            hysteresis: 4.0,
+            dwell_ms: 10_000,
+            fail_limit: 3,
+        }
+    }
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        ThermalConfig {
+            root: "/sys/class/thermal".to_string(),
+            zones: String::new(),
+            policy: "max".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+    pub enabled: bool,
+    pub path: String,
+    // octal file mode
+    pub mode: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    // address:port, 0.0.0.0 to reach it from the network
+    pub listen: String,
+}
+
+impl Default for DbusConfig {
+    fn default() -> Self {
+        DbusConfig {
+            enabled: false,
+            bus: "system".to_string(),
+            name: "org.lumi.IoService".to_string(),
+        }
+    }
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            enabled: false,
+            path: "/run/io-service/io.sock".to_string(),
+            mode: "0660".to_string(),
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            enabled: false,
+            listen: "127.0.0.1:8080".to_string(),
+        }
+    }
+}
+
+// Everything SystemIntergration needs, parsed and checked.
+pub struct Settings {
+    pub device: String,
+    pub id_mac: String,
+    pub keepalive_s: u64,
+    // password_file already resolved into password
+    pub mqtt: MqttConfig,
+    pub mqtt_tls: Option<Arc<rustls::ClientConfig>>,
+    pub backend: Arc<dyn GpioBackend>,
+    pub leds: Vec<PinSpec>,
+    pub ios: Vec<(PinSpec, Polarity)>,
+    // one per relay
+    pub relay_power_on: Vec<PowerOn>,
+    pub relay_state_file: Option<PathBuf>,
+    pub relay_interlock: Interlocks,
+    pub fans: Vec<PinSpec>,
+    pub network_led: Option<u64>,
+    pub button: PinSpec,
+    pub debounce_ms: u64,
+    pub gestures: GestureConfig,
+    pub gesture_map: GestureMap,
+    pub action_map: ActionMap,
+    pub fan_curve: FanCurve,
+    pub thermal: ThermalSource,
+    // None when dbus.enabled is false
+    pub dbus: Option<DbusConfig>,
+    // path and file mode, None when socket.enabled is false
+    pub socket: Option<(String, u32)>,
+    // None when http.enabled is false
+    pub http: Option<SocketAddr>,
+}
+
+fn field<T, E: std::fmt::Display>(key: &str, value: &str, result: Result<T, E>) -> Result<T, String> {
+    result.map_err(|e| format!("{} = {:?}: {}", key, value, e))
+}
+
+fn pins(key: &str, value: &str) -> Result<Vec<PinSpec>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| field(key, value, p.parse::<PinSpec>()))
+        .collect()
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        toml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // The password is masked, everything else is printed as loaded.
+    pub fn to_toml(&self) -> String {
+        let mut config = self.clone();
+        if !config.mqtt.password.is_empty() {
+            config.mqtt.password = "********".to_string();
+        }
+        toml::to_string(&config).unwrap()
+    }
+
+    pub fn validate(&self) -> Result<Settings, String> {
+        if self.device != "Ai" && self.device != "Hc" {
+            return Err(format!("device = {:?}: expected Ai or Hc", self.device));
+        }
+        if self.keepalive_s == 0 {
+            return Err("keepalive_s must be greater than 0".to_string());
+        }
+        let id_mac = if self.identity.id_mac.is_empty() {
+            let sources = field("identity.sources", &self.identity.sources, identity::parse_sources(&self.identity.sources))?;
+            let (source, id) = Identity::new(&self.identity.root, &self.identity.interface, sources).resolve()?;
+            log::info!("device id {} from {}", id, source);
+            id
+        }
+        else {
+            field("identity.id_mac", &self.identity.id_mac, identity::check_id(&self.identity.id_mac))?;
+            self.identity.id_mac.clone()
+        };
+
+        if !self.mqtt.enabled && !self.dbus.enabled && !self.socket.enabled && !self.http.enabled {
+            return Err("mqtt, dbus, socket and http are all disabled, no transport left".to_string());
+        }
+        // the broker settings only matter when mqtt is used
+        let mut mqtt = self.mqtt.clone();
+        let mut mqtt_tls = None;
+        if self.mqtt.enabled {
+            if self.mqtt.host.is_empty() {
+                return Err("mqtt.host must not be empty".to_string());
+            }
+            if self.mqtt.reconnect_min_ms == 0 || self.mqtt.reconnect_max_ms < self.mqtt.reconnect_min_ms {
+                return Err(format!(
+                    "mqtt.reconnect_min_ms = {} / mqtt.reconnect_max_ms = {}: min must be > 0 and not above max",
+                    self.mqtt.reconnect_min_ms, self.mqtt.reconnect_max_ms
+                ));
+            }
+            if self.mqtt.client_id.is_empty() {
+                return Err("mqtt.client_id must not be empty".to_string());
+            }
+            let publish = &self.mqtt.publish;
+            for (class, policy) in [("config", publish.config), ("status", publish.status), ("keepalive", publish.keepalive), ("event", publish.event), ("command", publish.command), ("error", publish.error)] {
+                if policy.qos > 2 {
+                    return Err(format!("mqtt.publish.{}.qos = {}: expected 0, 1 or 2", class, policy.qos));
+                }
+            }
+            let topics = &self.mqtt.topics;
+            for (key, topic) in [("status", &topics.status), ("config", &topics.config), ("keepalive", &topics.keepalive), ("command", &topics.command), ("event", &topics.event), ("presence", &topics.presence), ("error", &topics.error)] {
+                if topic.contains(['+', '#']) {
+                    return Err(format!("mqtt.topics.{} = {:?}: wildcards are only allowed in subscribe", key, topic));
+                }
+            }
+            if !mqtt.password_file.is_empty() {
+                if !mqtt.password.is_empty() {
+                    return Err("mqtt.password and mqtt.password_file are both set".to_string());
+                }
+                let password = std::fs::read_to_string(&mqtt.password_file)
+                    .map_err(|e| format!("mqtt.password_file = {:?}: {}", mqtt.password_file, e))?;
+                mqtt.password = password.trim_end_matches(['\r', '\n']).to_string();
+            }
+            if self.mqtt.tls.enabled {
+                mqtt_tls = Some(crate::transport::tls::client_config(&self.mqtt.tls)?);
+            }
+        }
+
+        let backend: Arc<dyn GpioBackend> = match self.gpio.backend.as_str() {
+            "sysfs" => Arc::new(SysfsBackend::new()),
+            "chardev" => Arc::new(ChardevBackend::new("io-service")),
+            "memory" => Arc::new(MemoryBackend::new()),
+            other => return Err(format!("gpio.backend = {:?}: expected sysfs, chardev or memory", other)),
+        };
+        if self.gpio.backend == "chardev" {
+            for (key, value) in [("gpio.leds", &self.gpio.leds), ("gpio.ios", &self.gpio.ios), ("gpio.fans", &self.gpio.fans), ("button.pin", &self.button.pin)] {
+                if pins(key, value)?.iter().any(|pin| matches!(pin.line, Line::Number(_))) {
+                    return Err(format!("{} = {:?}: the chardev backend needs gpiochipN:offset or a line name, not a sysfs number", key, value));
+                }
+            }
+        }
+
+        let leds = pins("gpio.leds", &self.gpio.leds)?;
+        let fans = pins("gpio.fans", &self.gpio.fans)?;
+        if let Some(led) = self.gpio.network_led {
+            if led as usize >= leds.len() {
+                return Err(format!("gpio.network_led = {}: only {} leds configured", led, leds.len()));
+            }
+        }
+        let mut polarity = self.gpio.relay_polarity.split(',').map(str::trim).filter(|p| !p.is_empty());
+        let mut ios = Vec::new();
+        for io in pins("gpio.ios", &self.gpio.ios)? {
+            let p = match polarity.next() {
+                Some(p) => field("gpio.relay_polarity", &self.gpio.relay_polarity, p.parse::<Polarity>())?,
+                None => Polarity::ActiveLow,
+            };
+            ios.push((io, p));
+        }
+        if polarity.next().is_some() {
+            return Err(format!("gpio.relay_polarity = {:?}: more entries than gpio.ios", self.gpio.relay_polarity));
+        }
+        let mut relay_power_on = Vec::new();
+        for p in self.gpio.relay_power_on.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            relay_power_on.push(field("gpio.relay_power_on", &self.gpio.relay_power_on, p.parse::<PowerOn>())?);
+        }
+        if relay_power_on.len() > ios.len() {
+            return Err(format!("gpio.relay_power_on = {:?}: more entries than gpio.ios", self.gpio.relay_power_on));
+        }
+        relay_power_on.resize(ios.len(), PowerOn::Last);
+        let relay_state_file = Some(PathBuf::from(&self.gpio.relay_state_file)).filter(|path| !path.as_os_str().is_empty());
+        let relay_interlock = field("gpio.relay_interlock", &self.gpio.relay_interlock, self.gpio.relay_interlock.parse::<Interlocks>())?;
+        for group in &relay_interlock.groups {
+            if let Some(relay) = group.relays.iter().find(|relay| **relay >= ios.len()) {
+                return Err(format!("gpio.relay_interlock = {:?}: relay {} but only {} ios configured", self.gpio.relay_interlock, relay, ios.len()));
+            }
+            let on = group.relays.iter().filter(|relay| relay_power_on[**relay] == PowerOn::On).count();
+            if on > 1 {
+                return Err(format!("gpio.relay_power_on = {:?}: interlocked relays {:?} are all on at start", self.gpio.relay_power_on, group.relays));
+            }
+        }
+
+        let button = field("button.pin", &self.button.pin, self.button.pin.parse::<PinSpec>())?;
+        let gesture_map = field("button.gestures", &self.button.gestures, self.button.gestures.parse::<GestureMap>())?;
+        let mut action_map = field("button.actions", &self.button.actions, self.button.actions.parse::<ActionMap>())?;
+        action_map.default_topic = self.mqtt.topics.command.clone();
+
+        let mut fan_curve = field("fan.curve", &self.fan.curve, self.fan.curve.parse::<FanCurve>())?;
+        // without fan pins the curve is only evaluated, nothing is driven
+        if let Some(level) = fan_curve.levels.iter().find(|l| !fans.is_empty() && l.pattern.len() != fans.len()) {
+            return Err(format!("fan.curve = {:?}: pattern {:?} does not match the {} fan pins", self.fan.curve, level.pattern, fans.len()));
+        }
+        fan_curve.hysteresis = self.fan.hysteresis;
+        fan_curve.min_dwell_ms = self.fan.dwell_ms;
+        fan_curve.fail_limit = self.fan.fail_limit;
+
+        let policy = field("thermal.policy", &self.thermal.policy, self.thermal.policy.parse::<Aggregation>())?;
+        let zone_types = self.thermal.zones.split(',').map(str::trim).filter(|z| !z.is_empty()).map(String::from).collect();
+
+        let dbus = match self.dbus.enabled {
+            false => None,
+            true => {
+                if self.dbus.bus != "system" && self.dbus.bus != "session" {
+                    return Err(format!("dbus.bus = {:?}: expected system or session", self.dbus.bus));
+                }
+                if self.dbus.name.split('.').count() < 2 || self.dbus.name.split('.').any(str::is_empty) {
+                    return Err(format!("dbus.name = {:?}: not a well-known bus name", self.dbus.name));
+                }
+                Some(self.dbus.clone())
+            }
+        };
+
+        let socket = match self.socket.enabled {
+            false => None,
+            true => {
+                if self.socket.path.is_empty() {
+                    return Err("socket.path must not be empty".to_string());
+                }
+                let mode = u32::from_str_radix(&self.socket.mode, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or(format!("socket.mode = {:?}: expected an octal mode like 0660", self.socket.mode))?;
+                Some((self.socket.path.clone(), mode))
+            }
+        };
+
+        let http = match self.http.enabled {
+            false => None,
+            true => Some(field("http.listen", &self.http.listen, self.http.listen.parse::<SocketAddr>())?),
+        };
+
+        Ok(Settings {
+            device: self.device.clone(),
+            id_mac,
+            keepalive_s: self.keepalive_s,
+            mqtt,
+            mqtt_tls,
+            backend,
+            leds,
+            ios,
+            relay_power_on,
+            relay_state_file,
+            relay_interlock,
+            fans,
+            network_led: self.gpio.network_led,
+            button,
+            debounce_ms: self.button.debounce_ms,
+            gestures: GestureConfig {
+                long_press_ms: self.button.long_press_ms,
+                double_click_ms: self.button.double_click_ms,
+                hold_stage_ms: self.gpio.time_blink_ms,
+            },
+            gesture_map,
+            action_map,
+            fan_curve,
+            thermal: ThermalSource::new(&self.thermal.root, zone_types, policy),
+            dbus,
+            socket,
+            http,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_partial_file() {
+        let config: Config = toml::from_str(r#"
+            device = "Ai"
+            [identity]
+            id_mac = "Mi8ea43769e4d6Qb"
+            [mqtt]
+            host = "10.10.60.1"
+            [mqtt.topics]
+            status = "site/io/status"
+            [gpio]
+            backend = "memory"
+            leds = "10,11"
+            ios = "20,21"
+            relay_polarity = "high"
+            fans = "30,31"
+        "#).unwrap();
+
+        assert_eq!(config.mqtt.host, "10.10.60.1");
+        assert_eq!(config.mqtt.port, 1883);
+        assert_eq!(config.mqtt.topics.status, "site/io/status");
+        assert_eq!(config.mqtt.topics.event, "component/io/event");
+
+        let settings = config.validate().unwrap();
+        assert_eq!(settings.leds.len(), 2);
+        assert_eq!(settings.ios[0].1, Polarity::ActiveHigh);
+        assert_eq!(settings.ios[1].1, Polarity::ActiveLow);
+
+        // what --print-config dumps loads back to the same config
+        assert_eq!(toml::from_str::<Config>(&config.to_toml()).unwrap(), config);
+    }
+
+    // the defaults with what the sandbox needs to validate
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.identity.id_mac = "Mi8ea43769e4d6Qb".to_string();
+        config.gpio.backend = "memory".to_string();
+        config.gpio.fans = "30,31".to_string();
+        config
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        assert!(toml::from_str::<Config>("[mqtt]\nhots = \"x\"").is_err());
+
+        let mut config = valid();
+        assert!(config.validate().is_ok());
+        config.identity.id_mac = "io-1".to_string();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("identity.id_mac"), "{}", err);
+
+        let mut config = valid();
+        config.gpio.leds = "10,gpiochip0:x".to_string();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("gpio.leds"), "{}", err);
+
+        let mut config = valid();
+        config.fan.curve = "0:0,50:1".to_string();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("fan.curve"), "{}", err);
+    }
+
+    #[test]
+    fn test_dbus_validation() {
+        let mut config = valid();
+        config.dbus.enabled = true;
+        config.dbus.bus = "user".to_string();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("dbus.bus"), "{}", err);
+    }
+
+    #[test]
+    fn test_socket_validation() {
+        let mut config = valid();
+        config.mqtt.enabled = false;
+        let err = config.validate().err().unwrap();
+        assert!(err.contains("no transport left"), "{}", err);
+
+        config.socket.enabled = true;
+        assert_eq!(config.validate().unwrap().socket, Some(("/run/io-service/io.sock".to_string(), 0o660)));
+        config.socket.mode = "0668".to_string();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("socket.mode"), "{}", err);
+    }
+
+    #[test]
+    fn test_http_validation() {
+        let mut config = valid();
+        config.http.enabled = true;
+        config.http.listen = "localhost".to_string();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("http.listen"), "{}", err);
+
+        config.http.listen = "127.0.0.1:8080".to_string();
+        assert_eq!(config.validate().unwrap().http, Some("127.0.0.1:8080".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_relay_power_on_validation() {
+        let mut config = valid();
+        config.gpio.ios = "20,21,22".to_string();
+        config.gpio.relay_power_on = "on, cloud".to_string();
+        let settings = config.validate().unwrap();
+        assert_eq!(settings.relay_power_on, vec![PowerOn::On, PowerOn::Cloud, PowerOn::Last]);
+        config.gpio.relay_power_on = "on,keep".to_string();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("gpio.relay_power_on"), "{}", err);
+    }
+
+    #[test]
+    fn test_relay_interlock_validation() {
+        let mut config = valid();
+        config.gpio.ios = "20,21,22".to_string();
+        config.gpio.relay_power_on = "on,on".to_string();
+        config.gpio.relay_interlock = "0+1:forbid".to_string();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("gpio.relay_power_on"), "{}", err);
+        config.gpio.relay_power_on = "on,last".to_string();
+        assert_eq!(config.validate().unwrap().relay_interlock.groups.len(), 1);
+        config.gpio.relay_interlock = "1+3:exclusive".to_string();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("gpio.relay_interlock"), "{}", err);
+    }
+
+    #[test]
+    fn test_mqtt_checked_only_when_enabled() {
+        let mut config = valid();
+        config.mqtt.host = String::new();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("mqtt.host"), "{}", err);
+
+        config.mqtt.enabled = false;
+        config.socket.enabled = true;
+        let settings = config.validate().unwrap();
+        assert!(settings.mqtt_tls.is_none());
+    }
+
+    #[test]
+    fn test_chardev_needs_chip_offsets() {
+        let mut config = valid();
+        config.gpio.backend = "chardev".to_string();
+        config.gpio.leds = "gpiochip0:10,LED_ZIGBEE".to_string();
+        config.gpio.ios = "0:20+active-low".to_string();
+        config.gpio.fans = String::new();
+        config.button.pin = "gpiochip1:4".to_string();
+        assert!(config.validate().is_ok());
+
+        config.gpio.ios = "0:20,480".to_string();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("gpio.ios") && err.contains("gpiochipN:offset"), "{}", err);
+    }
+
+    #[test]
+    fn test_password_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("mqtt.pass");
+        std::fs::write(&file, "s3cret\n").unwrap();
+
+        let mut config = valid();
+        config.mqtt.username = "component".to_string();
+        config.mqtt.password_file = file.to_string_lossy().to_string();
+        assert_eq!(config.validate().unwrap().mqtt.password, "s3cret");
+
+        config.mqtt.password = "123".to_string();
+        assert!(config.validate().is_err());
+        assert!(config.to_toml().contains("password = \"********\""));
+    }
+}
@@ -4,12 +4,77 @@ pub enum OtaErr {
     SetValueErr,
     SelectPinErr,
     SetDirectionErr,
+    SetEdgeErr,
     GetValueErr,
+    ReadbackErr,
     HttpErr,
     MqttErr,
+    DbusErr,
+    SocketErr,
     TimoutErr,
     RepeatErr,
     OpenFileErr,
     ReadFileErr,
     ConvertTempErr,
-}
\ No newline at end of file
+    ActionErr,
+    InterlockErr,
+}
+
+impl OtaErr {
+    // what goes on the wire as "error", kept apart from the variant names
+    pub fn reason(&self) -> &'static str {
+        match self {
+            OtaErr::SetValueErr => "write_failed",
+            OtaErr::SelectPinErr => "unknown_relay",
+            OtaErr::SetDirectionErr => "direction_failed",
+            OtaErr::SetEdgeErr => "edge_failed",
+            OtaErr::GetValueErr => "read_failed",
+            OtaErr::ReadbackErr => "readback_failed",
+            OtaErr::HttpErr => "http_failed",
+            OtaErr::MqttErr => "mqtt_failed",
+            OtaErr::DbusErr => "dbus_failed",
+            OtaErr::SocketErr => "socket_failed",
+            OtaErr::TimoutErr => "timeout",
+            OtaErr::RepeatErr => "repeated",
+            OtaErr::OpenFileErr => "open_failed",
+            OtaErr::ReadFileErr => "read_failed",
+            OtaErr::ConvertTempErr => "bad_temperature",
+            OtaErr::ActionErr => "action_failed",
+            OtaErr::InterlockErr => "interlocked",
+        }
+    }
+}
+
+// An io protocol message that cannot be used.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProtocolErr {
+    // not JSON, or not an envelope
+    Malformed(String),
+    Missing(&'static str),
+    // a field that does not hold what it should, e.g. a non-numeric event_code
+    Invalid { field: &'static str, value: String },
+    UnknownCmd(String),
+}
+
+impl ProtocolErr {
+    // counter key and "reason" of the error reply
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ProtocolErr::Malformed(_) => "malformed",
+            ProtocolErr::Missing(_) => "missing_field",
+            ProtocolErr::Invalid { .. } => "invalid_field",
+            ProtocolErr::UnknownCmd(_) => "unknown_cmd",
+        }
+    }
+}
+
+impl std::fmt::Display for ProtocolErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolErr::Malformed(reason) => write!(f, "malformed message: {}", reason),
+            ProtocolErr::Missing(field) => write!(f, "{} is missing", field),
+            ProtocolErr::Invalid { field, value } => write!(f, "{} = {:?}: invalid", field, value),
+            ProtocolErr::UnknownCmd(cmd) => write!(f, "unknown cmd {:?}, expected set or get", cmd),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::str::FromStr;
+use crate::error::OtaErr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanLevel {
+    // temperature (°C) at which this level is entered while heating up
+    pub temp: f32,
+    // value written to each fan pin, in the order of --fans
+    pub pattern: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanCurve {
+    pub levels: Vec<FanLevel>,
+    // a level is left downwards only below `temp - hysteresis`
+    pub hysteresis: f32,
+    // minimum time spent on a level before the next change
+    pub min_dwell_ms: u64,
+    // consecutive failed reads before jumping to the last level
+    pub fail_limit: u32,
+}
+
+impl Default for FanCurve {
+    fn default() -> Self {
+        FanCurve {
+            levels: vec![
+                FanLevel { temp: 0.0, pattern: vec![0, 0] },
+                FanLevel { temp: 52.0, pattern: vec![0, 1] },
+                FanLevel { temp: 62.0, pattern: vec![1, 0] },
+            ],
+            hysteresis: 4.0,
+            min_dwell_ms: 10_000,
+            fail_limit: 3,
+        }
+    }
+}
+
+// "temp:pattern" points, e.g. "0:00,52:01,62:10"
+pub fn parse_levels(s: &str) -> Result<Vec<FanLevel>, String> {
+    let mut levels: Vec<FanLevel> = Vec::new();
+    for point in s.split(',') {
+        let (temp, pattern) = point.split_once(':').ok_or_else(|| format!("fan point {:?} is not temp:pattern", point))?;
+        let temp = temp.trim().parse::<f32>().map_err(|_| format!("invalid fan temperature {:?}", temp))?;
+        let pattern = pattern
+            .trim()
+            .chars()
+            .map(|c| match c {
+                '0' => Ok(0),
+                '1' => Ok(1),
+                _ => Err(format!("invalid fan pattern {:?}", pattern)),
+            })
+            .collect::<Result<Vec<u8>, String>>()?;
+
+        if let Some(last) = levels.last() {
+            if temp <= last.temp {
+                return Err(format!("fan curve temperatures must increase, {} after {}", temp, last.temp));
+            }
+        }
+        levels.push(FanLevel { temp, pattern });
+    }
+    Ok(levels)
+}
+
+impl FromStr for FanCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(FanCurve {
+            levels: parse_levels(s)?,
+            ..FanCurve::default()
+        })
+    }
+}
+
+pub struct FanController {
+    curve: FanCurve,
+    level: Option<usize>,
+    since_ms: u64,
+    failures: u32,
+}
+
+impl FanController {
+    pub fn new(curve: FanCurve) -> Self {
+        FanController {
+            curve,
+            level: None,
+            since_ms: 0,
+            failures: 0,
+        }
+    }
+
+    pub fn level(&self) -> Option<usize> {
+        self.level
+    }
+
+    pub fn pattern(&self, level: usize) -> Vec<u8> {
+        self.curve.levels.get(level).map(|l| l.pattern.clone()).unwrap_or_default()
+    }
+
+    fn target(&self, temp: f32) -> usize {
+        let levels = &self.curve.levels;
+        let mut target = self.level.unwrap_or(0);
+
+        while target + 1 < levels.len() && temp >= levels[target + 1].temp {
+            target += 1;
+        }
+        // first reading: no hysteresis, just pick the matching level
+        if self.level.is_none() {
+            return target;
+        }
+        while target > 0 && temp < levels[target].temp - self.curve.hysteresis {
+            target -= 1;
+        }
+        target
+    }
+
+    // Feed a temperature reading, returns the level to switch to if any.
+    pub fn update(&mut self, reading: Result<f32, OtaErr>, now_ms: u64) -> Option<usize> {
+        if self.curve.levels.is_empty() {
+            return None;
+        }
+        let max = self.curve.levels.len() - 1;
+
+        let target = match reading {
+            Ok(temp) => {
+                self.failures = 0;
+                let target = self.target(temp);
+                if self.level.is_some() && now_ms.saturating_sub(self.since_ms) < self.curve.min_dwell_ms {
+                    return None;
+                }
+                target
+            }
+            Err(e) => {
+                self.failures += 1;
+                log::warn!("fan: temperature read failed ({:?}), {} in a row", e, self.failures);
+                if self.failures < self.curve.fail_limit {
+                    return None;
+                }
+                max
+            }
+        };
+
+        if self.level == Some(target) {
+            return None;
+        }
+        log::info!("fan: level {:?} -> {}", self.level, target);
+        self.level = Some(target);
+        self.since_ms = now_ms;
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn controller() -> FanController {
+        FanController::new(FanCurve {
+            min_dwell_ms: 1000,
+            ..FanCurve::default()
+        })
+    }
+
+    #[test]
+    fn test_parse_levels() {
+        let levels = parse_levels("0:00,52:01,62:10").unwrap();
+        assert_eq!(levels, FanCurve::default().levels);
+        assert!(parse_levels("0:00,52:0x").is_err());
+        assert!(parse_levels("52:00,40:01").is_err());
+    }
+
+    #[test]
+    fn test_hysteresis_and_dwell() {
+        let mut fan = controller();
+        assert_eq!(fan.update(Ok(40.0), 0), Some(0));
+        assert_eq!(fan.update(Ok(53.0), 500), None); // dwell
+        assert_eq!(fan.update(Ok(53.0), 1000), Some(1));
+        assert_eq!(fan.update(Ok(49.0), 3000), None); // inside the band
+        assert_eq!(fan.update(Ok(47.0), 4000), Some(0));
+        assert_eq!(fan.update(Ok(70.0), 5000), Some(2));
+    }
+
+    #[test]
+    fn test_fail_safe() {
+        let mut fan = controller();
+        assert_eq!(fan.update(Ok(40.0), 0), Some(0));
+        assert_eq!(fan.update(Err(OtaErr::ReadFileErr), 100), None);
+        assert_eq!(fan.update(Err(OtaErr::ReadFileErr), 200), None);
+        assert_eq!(fan.update(Err(OtaErr::ReadFileErr), 300), Some(2));
+        assert_eq!(fan.level(), Some(2));
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Gesture {
+    ShortPress,
+    LongPress { held_ms: u64 },
+    DoubleClick,
+    // still held, fired every hold_stage_ms
+    HoldStage { n: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GestureConfig {
+    // a press held at least this long is a long press
+    pub long_press_ms: u64,
+    // max gap between release and the next press of a double click
+    pub double_click_ms: u64,
+    // hold stage period, 0 disables hold stages
+    pub hold_stage_ms: u64,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        GestureConfig {
+            long_press_ms: 1000,
+            double_click_ms: 300,
+            hold_stage_ms: 1000,
+        }
+    }
+}
+
+// Turns debounced press/release edges into gestures. Time is passed in by the
+// caller so it can be driven from tests.
+pub struct GestureRecognizer {
+    config: GestureConfig,
+    pressed_at: Option<u64>,
+    stage: u32,
+    second_press: bool,
+    released_at: Option<u64>,
+}
+
+impl GestureRecognizer {
+    pub fn new(config: GestureConfig) -> Self {
+        GestureRecognizer {
+            config,
+            pressed_at: None,
+            stage: 0,
+            second_press: false,
+            released_at: None,
+        }
+    }
+
+    pub fn press(&mut self, now_ms: u64) -> Vec<Gesture> {
+        let mut out = self.tick(now_ms);
+        self.second_press = self.released_at.take().is_some();
+        self.pressed_at = Some(now_ms);
+        self.stage = 0;
+        out.extend(self.tick(now_ms));
+        out
+    }
+
+    pub fn release(&mut self, now_ms: u64) -> Vec<Gesture> {
+        let mut out = self.tick(now_ms);
+        let Some(pressed_at) = self.pressed_at.take() else {
+            return out;
+        };
+        let held_ms = now_ms.saturating_sub(pressed_at);
+
+        if held_ms >= self.config.long_press_ms {
+            out.push(Gesture::LongPress { held_ms });
+        }
+        else if self.second_press {
+            out.push(Gesture::DoubleClick);
+        }
+        else {
+            // wait for a possible second click before calling it short
+            self.released_at = Some(now_ms);
+        }
+        self.second_press = false;
+        out
+    }
+
+    pub fn tick(&mut self, now_ms: u64) -> Vec<Gesture> {
+        let mut out = Vec::new();
+        if let Some(pressed_at) = self.pressed_at {
+            if let Some(stage) = now_ms.saturating_sub(pressed_at).checked_div(self.config.hold_stage_ms) {
+                let stage = stage as u32;
+                while self.stage < stage {
+                    self.stage += 1;
+                    out.push(Gesture::HoldStage { n: self.stage });
+                }
+            }
+        }
+        if let Some(released_at) = self.released_at {
+            if now_ms.saturating_sub(released_at) >= self.config.double_click_ms {
+                self.released_at = None;
+                out.push(Gesture::ShortPress);
+            }
+        }
+        out
+    }
+
+    // Next time `tick` has something to report, if any.
+    pub fn next_deadline(&self) -> Option<u64> {
+        let hold = match self.pressed_at {
+            Some(pressed_at) if self.config.hold_stage_ms > 0 => {
+                Some(pressed_at + (self.stage as u64 + 1) * self.config.hold_stage_ms)
+            }
+            _ => None,
+        };
+        let click = self.released_at.map(|r| r + self.config.double_click_ms);
+        match (hold, click) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+// Which named action a gesture triggers. Keys are "short", "double", "long"
+// and "hold<N>" for a long press released at hold stage N.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GestureMap {
+    actions: HashMap<String, String>,
+}
+
+impl GestureMap {
+    pub fn action(&self, gesture: &Gesture, hold_stage: u32) -> Option<String> {
+        match gesture {
+            Gesture::ShortPress => self.actions.get("short").cloned(),
+            Gesture::DoubleClick => self.actions.get("double").cloned(),
+            Gesture::LongPress { .. } => self
+                .actions
+                .get(&format!("hold{}", hold_stage))
+                .or_else(|| self.actions.get("long"))
+                .cloned(),
+            Gesture::HoldStage { .. } => None,
+        }
+    }
+}
+
+// "double=pairing_mode,hold4=factory_reset"
+impl FromStr for GestureMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut actions = HashMap::new();
+        for item in s.split(',').filter(|i| !i.is_empty()) {
+            let (gesture, action) = item.split_once('=').ok_or_else(|| format!("gesture {:?} is not gesture=action", item))?;
+            let valid = matches!(gesture, "short" | "double" | "long")
+                || gesture.strip_prefix("hold").is_some_and(|n| n.parse::<u32>().is_ok());
+            if !valid {
+                return Err(format!("unknown gesture {:?}", gesture));
+            }
+            actions.insert(gesture.to_string(), action.to_string());
+        }
+        Ok(GestureMap { actions })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn recognizer() -> GestureRecognizer {
+        GestureRecognizer::new(GestureConfig::default())
+    }
+
+    #[test]
+    fn test_short_and_double() {
+        let mut g = recognizer();
+        assert!(g.press(0).is_empty());
+        assert!(g.release(100).is_empty());
+        assert_eq!(g.next_deadline(), Some(400));
+        assert_eq!(g.tick(400), vec![Gesture::ShortPress]);
+
+        assert!(g.press(1000).is_empty());
+        assert!(g.release(1100).is_empty());
+        assert!(g.press(1200).is_empty());
+        assert_eq!(g.release(1300), vec![Gesture::DoubleClick]);
+        assert_eq!(g.tick(5000), vec![]);
+    }
+
+    #[test]
+    fn test_hold_stages_and_long_press() {
+        let mut g = recognizer();
+        g.press(0);
+        assert_eq!(g.next_deadline(), Some(1000));
+        assert_eq!(g.tick(1000), vec![Gesture::HoldStage { n: 1 }]);
+        assert_eq!(g.tick(2500), vec![Gesture::HoldStage { n: 2 }]);
+        assert_eq!(g.release(3100), vec![Gesture::HoldStage { n: 3 }, Gesture::LongPress { held_ms: 3100 }]);
+        assert_eq!(g.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_gesture_map() {
+        let map = "double=pairing_mode,long=reboot,hold4=factory_reset".parse::<GestureMap>().unwrap();
+        assert_eq!(map.action(&Gesture::DoubleClick, 0), Some("pairing_mode".to_string()));
+        assert_eq!(map.action(&Gesture::LongPress { held_ms: 4200 }, 4), Some("factory_reset".to_string()));
+        assert_eq!(map.action(&Gesture::LongPress { held_ms: 2000 }, 2), Some("reboot".to_string()));
+        assert_eq!(map.action(&Gesture::ShortPress, 0), None);
+        assert!("triple=x".parse::<GestureMap>().is_err());
+    }
+}
@@ -1,10 +1,16 @@
-use sysfs_gpio::{Direction,Pin};
+pub mod line;
+pub mod sysfs;
+pub mod chardev;
+pub mod memory;
 use crate::error::OtaErr;
-use tokio::time::{sleep,Duration};
+use tokio::time::{sleep,timeout,Duration};
 use tokio::sync::mpsc;
-use std::sync::{Arc, Mutex};
-use tokio::fs::File;
-use tokio::io::{self, AsyncReadExt};
+use std::sync::Arc;
+use futures::{Stream, StreamExt};
+use line::PinSpec;
+use crate::gesture::{Gesture, GestureConfig, GestureRecognizer};
+use crate::transport::LedMode;
+use tokio::time::Instant;
 
 macro_rules! ON {
     () => { 0 };
@@ -18,6 +24,85 @@ macro_rules! BLINK {
     () => { 2 };
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Direction {
+    In,
+    Out,
+    // output that starts at this level, set in one step so the line never
+    // passes through the other one
+    High,
+    Low,
+}
+
+impl Direction {
+    pub fn output(value: u8) -> Direction {
+        match value {
+            0 => Direction::Low,
+            _ => Direction::High,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+// Electrical level that switches a relay on.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Polarity {
+    ActiveHigh,
+    #[default]
+    ActiveLow,
+}
+
+impl Polarity {
+    pub fn level(&self, on: bool) -> u8 {
+        match (self, on) {
+            (Polarity::ActiveLow, true) | (Polarity::ActiveHigh, false) => 0,
+            _ => 1,
+        }
+    }
+}
+
+impl std::str::FromStr for Polarity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "high" | "active-high" => Ok(Polarity::ActiveHigh),
+            "low" | "active-low" => Ok(Polarity::ActiveLow),
+            _ => Err(format!("invalid relay polarity {:?}", s)),
+        }
+    }
+}
+
+pub type EdgeStream = std::pin::Pin<Box<dyn Stream<Item = Result<u8, OtaErr>> + Send>>;
+
+// Everything the drivers need from the GPIO hardware, so they can run on top of
+// sysfs, the gpiochip character device or an in-memory fake.
+pub trait GpioBackend: Send + Sync {
+    fn export(&self, pin: &PinSpec) -> Result<(), OtaErr>;
+    fn set_direction(&self, pin: &PinSpec, direction: Direction) -> Result<(), OtaErr>;
+    fn get_value(&self, pin: &PinSpec) -> Result<u8, OtaErr>;
+    fn set_value(&self, pin: &PinSpec, value: u8) -> Result<(), OtaErr>;
+    fn edge_stream(&self, pin: &PinSpec, edge: Edge) -> Result<EdgeStream, OtaErr>;
+
+    // Done once per output pin; later writes only go through set_value.
+    fn setup_output(&self, pin: &PinSpec, value: u8) -> Result<(), OtaErr> {
+        self.export(pin)?;
+        self.set_direction(pin, Direction::output(value))
+    }
+
+    fn read_pin(&self, pin: &PinSpec) -> Result<u8, OtaErr> {
+        self.export(pin)?;
+        self.set_direction(pin, Direction::In)?;
+        self.get_value(pin)
+    }
+}
+
 
 
 pub enum GpioIn {
@@ -28,25 +113,24 @@ pub enum GpioIn {
     RelayOn {pin:u64},
     RelayOff {pin:u64},
 
-    ButonBlink, 
+    // light the first `stage` LEDs (all of them past the last one) while the
+    // button is held
+    HoldProgress {stage: u32},
     ReturnState,
+    // acknowledge a button action on all LEDs: 3 short flashes on success,
+    // one long flash on failure, then back to the cached states
+    Confirm {ok: bool},
 
-    FanModeLv1,
-    FanModeLv2,
-    FanModeLv3,
+    FanMode {level: usize, pattern: Vec<u8>},
 
 
 }
 #[derive(Clone)]
 pub enum GpioOut {
-    Stop,
     LedBlinkContinue{led_pin :u64, blink:bool, time: u16, fre: u16},
     ButtonPressed,
     ButtonReleased,
-    LedTwoReleased,
-    LedThreeReleased,
-    LedFourReleased,
-    LedFiveReleased,
+    Gesture(Gesture),
 }
 #[derive(PartialEq)]
 pub enum StatusGpio {
@@ -56,55 +140,117 @@ pub enum StatusGpio {
 }
 
 pub struct GpioDriver {
-    leds:Vec<(Pin, u8, u64,u8, u16)>,
-    fan:Vec<Pin>,
-    io:Vec<(Pin, u8)>,
-    button_pin:u64,
+    backend: Arc<dyn GpioBackend>,
+    leds:Vec<(PinSpec, u8, u64,u8, u16)>,
+    fan:Vec<PinSpec>,
+    io:Vec<(PinSpec, u8, Polarity)>,
     pub status: StatusGpio,
     pub tx: mpsc::Sender<Result<GpioOut, OtaErr>>,
     pub rx: mpsc::Receiver<Result<GpioOut, OtaErr>>,
-    time_blink: u64,
-    pub last_cpu_temperature:u32,
 }
 
 
 pub struct ButtonDriver {
-    button: Pin,
+    backend: Arc<dyn GpioBackend>,
+    button: PinSpec,
+    debounce: Duration,
+    gestures: GestureConfig,
     pub tx: mpsc::Sender<Result<GpioOut, OtaErr>>,
     pub rx: mpsc::Receiver<Result<GpioOut, OtaErr>>,
-    temp: Arc<Mutex<u8>>, // Biến temp được đặt ở đây
 }
 
 impl ButtonDriver {
-    pub fn new(button_pin: u64) -> ButtonDriver {
+    pub fn new(backend: Arc<dyn GpioBackend>, button_pin: PinSpec, debounce: Duration, gestures: GestureConfig) -> ButtonDriver {
         let (tx, rx) = mpsc::channel::<Result<GpioOut, OtaErr>>(5);
         ButtonDriver {
-            button: Pin::new(button_pin),
-            tx: tx,
-            rx: rx,
-            temp: Arc::new(Mutex::new(1)), // Khởi tạo temp với giá trị ban đầu là 0
+            backend,
+            button: button_pin,
+            debounce,
+            gestures,
+            tx,
+            rx,
         }
     }
 
-    pub async fn button_handle(&mut self) -> Result<(), OtaErr> {
-        self.button.export().map_err(|_| OtaErr::SelectPinErr)?;
-        self.button.set_direction(Direction::In).map_err(|_| OtaErr::SetDirectionErr)?;
-
-        let val = self.button.get_value().unwrap();
-        // Sử dụng temp_clone để cập nhật temp_value
-        let mut temp_guard = self.temp.lock().unwrap();
-        if *temp_guard != val {
-            if val == 0 {
-                log::info!("Button pressed");
-                let _ = self.tx.send(Ok(GpioOut::ButtonPressed)).await;
-            }
-            else {
-                log::info!("Button released");
-                let _ = self.tx.send(Ok(GpioOut::ButtonReleased)).await;
+    // Wait for edges on the button line instead of polling it. After an edge
+    // the line has to stay quiet for the debounce window before it is sampled.
+    // Raw press/release events are followed by the recognised gestures.
+    pub fn start(&mut self) -> Result<(), OtaErr> {
+        self.backend.export(&self.button)?;
+        self.backend.set_direction(&self.button, Direction::In)?;
+        let mut edges = self.backend.edge_stream(&self.button, Edge::Both)?;
+        let mut last = self.backend.get_value(&self.button)?;
+
+        let backend = self.backend.clone();
+        let button = self.button.clone();
+        let debounce = self.debounce;
+        let mut gestures = GestureRecognizer::new(self.gestures.clone());
+        let tx = self.tx.clone();
+
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let now_ms = || started.elapsed().as_millis() as u64;
+            loop {
+                let edge = match gestures.next_deadline() {
+                    Some(deadline) => {
+                        let wait = Duration::from_millis(deadline.saturating_sub(now_ms()));
+                        match timeout(wait, edges.next()).await {
+                            Ok(edge) => edge,
+                            Err(_) => {
+                                for gesture in gestures.tick(now_ms()) {
+                                    if tx.send(Ok(GpioOut::Gesture(gesture))).await.is_err() {
+                                        return;
+                                    }
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    None => edges.next().await,
+                };
+                let Some(edge) = edge else {
+                    log::error!("button: edge stream ended, button disabled");
+                    return;
+                };
+                if let Err(e) = edge {
+                    log::error!("button edge: {:?}", e);
+                    continue;
+                }
+                loop {
+                    match timeout(debounce, edges.next()).await {
+                        Ok(Some(_)) => continue,
+                        Ok(None) => return,
+                        Err(_) => break,
+                    }
+                }
+
+                let val = match backend.get_value(&button) {
+                    Ok(val) => val,
+                    Err(e) => {
+                        log::error!("button value: {:?}", e);
+                        continue;
+                    }
+                };
+                if val == last {
+                    continue;
+                }
+                last = val;
+                let (event, recognised) = if val == 0 {
+                    log::info!("Button pressed");
+                    (GpioOut::ButtonPressed, gestures.press(now_ms()))
+                }
+                else {
+                    log::info!("Button released");
+                    (GpioOut::ButtonReleased, gestures.release(now_ms()))
+                };
+                let events = std::iter::once(event).chain(recognised.into_iter().map(GpioOut::Gesture));
+                for event in events {
+                    if tx.send(Ok(event)).await.is_err() {
+                        return;
+                    }
+                }
             }
-            *temp_guard = val;
-        }
-        drop(temp_guard);
+        });
         Ok(())
     }
 
@@ -116,50 +262,57 @@ impl ButtonDriver {
 
 
 
-pub async fn toogle_led(pin: Pin) {
-    if pin.get_value().unwrap() == 0 {
-        pin.set_value(1).unwrap();
+pub async fn toogle_led(backend: &dyn GpioBackend, pin: &PinSpec) -> Result<(), OtaErr> {
+    if backend.get_value(pin)? == 0 {
+        backend.set_value(pin, 1)
     }else {
-        pin.set_value(0).unwrap();
+        backend.set_value(pin, 0)
     }
 }
 
+// Make the pin an output without changing its level.
+fn keep_output(backend: &dyn GpioBackend, pin: &PinSpec) -> Result<(), OtaErr> {
+    backend.export(pin)?;
+    let value = backend.get_value(pin)?;
+    backend.set_direction(pin, Direction::output(value))
+}
+
 impl GpioDriver {
-    pub fn new(led_vec:Vec<u64>, io_vec:Vec<u64>, fan_vec:Vec<u64>,time_blink:u64, button_pin:u64) -> GpioDriver {
+    pub fn new(backend: Arc<dyn GpioBackend>, led_vec:Vec<PinSpec>, io_vec:Vec<(PinSpec, Polarity)>, fan_vec:Vec<PinSpec>) -> GpioDriver {
         let (tx, rx) = mpsc::channel::<Result<GpioOut, OtaErr>>(5);
 
 
         // config leds 
         let mut leds = Vec::new();
         for pin in led_vec {
-            let led_pin = Pin::new(pin);
-            leds.push((led_pin, 1, 0, 0, 0)); // Initialize LED state, assuming 0 for OFF
+            if let Err(e) = backend.setup_output(&pin, OFF!()) {
+                log::error!("led {}: {:?}", pin.line, e);
+            }
+            leds.push((pin, 1, 0, 0, 0)); // Initialize LED state, assuming 1 for OFF
         }
 
-        // config io
+        // config io, relays and fans keep the level they have until driven
         let mut ios = Vec::new();
-        for io in io_vec {
-            let io_pin = Pin::new(io);
-            ios.push((io_pin, 1)); // Initialize
+        for (io, polarity) in io_vec {
+            if let Err(e) = keep_output(backend.as_ref(), &io) {
+                log::error!("relay {}: {:?}", io.line, e);
+            }
+            ios.push((io, OFF!(), polarity)); // Initialize
         }
-
-        // config fan 
-        let mut fans = Vec::new();
-        for fan in fan_vec {
-            let fan_pin = Pin::new(fan);
-            fans.push(fan_pin); // Initialize
+        for pin in &fan_vec {
+            if let Err(e) = keep_output(backend.as_ref(), pin) {
+                log::error!("fan {}: {:?}", pin.line, e);
+            }
         }
 
         GpioDriver {
-            leds:leds,
-            fan:fans,
-            io:ios,
-            button_pin: button_pin,
+            backend,
+            leds,
+            fan: fan_vec,
+            io: ios,
             status: StatusGpio::LedCtrl,
-            tx: tx,
-            rx: rx,
-            time_blink: time_blink,
-            last_cpu_temperature:0,
+            tx,
+            rx,
         }
     }
 
@@ -171,28 +324,24 @@ impl GpioDriver {
         match event {
             GpioIn::LedOn{pin} => {
                 log::info!("on");
-                if let Some((led, state, _, _, _)) = self.leds.iter_mut().nth(pin as usize) {
+                if let Some((led, state, _, _, _)) = self.leds.get_mut(pin as usize) {
                     *state = ON!();
-                    // led.export().map_err(|_| {OtaErr::SelectPinErr})?;
-                    // led.set_direction(Direction::Out).map_err(|_| {OtaErr::SetDirectionErr})?;
-                    // led.set_value(0).map_err(|_| {OtaErr::SetValueErr})?; 
+                    self.backend.set_value(led, ON!())?;
                 }
                 Ok(())
             }
 
             GpioIn::LedOff{pin} => {
                 log::info!("off");
-                if let Some((led, state,_, _, _)) = self.leds.iter_mut().nth(pin as usize) {
+                if let Some((led, state,_, _, _)) = self.leds.get_mut(pin as usize) {
                     *state = OFF!();
-                    // led.export().map_err(|_| {OtaErr::SelectPinErr})?;
-                    // led.set_direction(Direction::Out).map_err(|_| {OtaErr::SetDirectionErr})?;
-                    // led.set_value(1).map_err(|_| {OtaErr::SetValueErr})?; 
+                    self.backend.set_value(led, OFF!())?;
                 }
                 Ok(())
             }
 
             GpioIn::LedBlink{time,blink, fre, pin, get_tick} => {
-                if let Some((led,state, tick, index, temp)) = self.leds.iter_mut().nth(pin as usize) { 
+                if let Some((led,state, tick, index, temp)) = self.leds.get_mut(pin as usize) { 
                     log::info!("Temp:{}",temp);
                     if *temp == fre {
                         let _fre = fre /100;
@@ -207,249 +356,286 @@ impl GpioDriver {
                         if get_tick - *tick >= _fre as u64{ 
                             *tick = get_tick;
                             *index += 1;                
-                            //toogle_led(*led).await;
+                            toogle_led(self.backend.as_ref(), led).await?;
                             log::info!("Time:{}, fre:{}",time,fre);
                             log::info!("Toggle led");
     
     
-                            if blink == false {
-                                if *index >= time as u8 {
-                                    *index = 0;
-                                    return Ok(());
-                                }
-                            }  
+                            if !blink && *index >= time as u8 {
+                                *index = 0;
+                                return Ok(());
+                            }
                         }
-                        let state_clone = state.clone();
+                        let state_clone = *state;
                         let tx_clone = self.tx.clone();
-                        let _ = tokio::spawn(async move{
+                        tokio::spawn(async move{
                             if state_clone == BLINK!() {
                                 sleep(Duration::from_millis(fre.into())).await;
-                                tx_clone.send(Ok(GpioOut::LedBlinkContinue{led_pin :pin, blink:blink, time: time, fre: fre})).await.unwrap();
+                                tx_clone.send(Ok(GpioOut::LedBlinkContinue{led_pin :pin, blink, time, fre})).await.unwrap();
                             }    
                         });
                     }
                 } 
                 Ok(())
             }   
-            GpioIn::ButonBlink => {
-                let led_clone = self.leds.clone();
-                let button = Pin::new(self.button_pin);
-                let time_clone = self.time_blink.clone();
-                let tx_clone = self.tx.clone();
-                
-                let _ = tokio::spawn(async move{
-                    for &(led, _, _,_ , _) in &led_clone 
-                    {
-                        led.export().map_err(|_| {OtaErr::SelectPinErr}).unwrap();
-                        led.set_direction(Direction::Out).map_err(|_| {OtaErr::SetDirectionErr}).unwrap();
-                        led.set_value(1).map_err(|_| {OtaErr::SetValueErr}).unwrap();
-                    }
-                    for (index, &(led, _, _,_ , _)) in led_clone.iter().enumerate() {
-                        
-                        sleep(Duration::from_millis(time_clone)).await;
-                        if button.get_value().unwrap() == 1 {
-                            
-                            break;
-                        }
-                      
-                        led.set_value(0).map_err(|_| {OtaErr::SetValueErr}).unwrap();
-                        
-                        if index == led_clone.len() - 1 {
-                            sleep(Duration::from_millis(time_clone)).await;
-                        }
-                    }
-                    if button.get_value().unwrap() == 0 {
-                        for &(led, _, _,_ , _) in &led_clone {
-                            if button.get_value().unwrap() == 1 {
-                                break;
-                            }
-                            led.set_value(1).map_err(|_| {OtaErr::SetValueErr}).unwrap();
-                        }
-                       let _ = tx_clone.send(Ok(GpioOut::ButtonPressed)).await.unwrap(); 
-                    }  
-                });
-
-                
+            GpioIn::HoldProgress{stage} => {
+                let lit = (stage as usize).min(self.leds.len());
+                for (index, (led, _, _,_ , _)) in self.leds.iter().enumerate() {
+                    let value = if index < lit { ON!() } else { OFF!() };
+                    self.backend.set_value(led, value)?;
+                }
                 Ok(())
             }
 
             GpioIn::ReturnState => {
-                // get mode
-                for (mut index, &(led, _, _,_ , _)) in self.leds.iter().enumerate() {
-                    let status = led.get_value().map_err(|_| {OtaErr::SetValueErr}).unwrap();
-                    if status == OFF!() {
-                        index  -= 1;
-                        let event = match index {
-                            0 => GpioOut::LedTwoReleased,
-                            1 => GpioOut::LedThreeReleased,
-                            2 => GpioOut::LedFourReleased,
-                            3 => GpioOut::LedFiveReleased,
-                            _ => GpioOut::Stop,
-                        }; 
-                        let _ = self.tx.send(Ok(event)).await.unwrap();
-                        break;
-                    }
-                }
                 // return state 
                 for (led, state, _,_ , _) in &self.leds{
-                    // Extract pin and state from the tuple
-                    // Process pin and state here
-                    if *state == BLINK!() {
-
-                    }
-                    else {
-                        led.set_value(*state).map_err(|_| {OtaErr::SetValueErr}).unwrap();
+                    if *state != BLINK!() {
+                        self.backend.set_value(led, *state)?;
                     }
                 }
                 Ok(())
             }
+            GpioIn::Confirm{ok} => {
+                let leds = self.leds.clone();
+                let backend = self.backend.clone();
+                let (flashes, on_ms) = if ok { (3, 150) } else { (1, 1000) };
+
+                tokio::spawn(async move {
+                    for _ in 0..flashes {
+                        for (led, _, _, _, _) in &leds {
+                            let _ = backend.set_value(led, ON!());
+                        }
+                        sleep(Duration::from_millis(on_ms)).await;
+                        for (led, _, _, _, _) in &leds {
+                            let _ = backend.set_value(led, OFF!());
+                        }
+                        sleep(Duration::from_millis(150)).await;
+                    }
+                    for (led, state, _, _, _) in &leds {
+                        if *state != BLINK!() {
+                            let _ = backend.set_value(led, *state);
+                        }
+                    }
+                });
+                Ok(())
+            }
+
             GpioIn::RelayOn{pin:relay} => {
                 log::info!("on");
-                if let Some((relay, state)) = self.io.iter_mut().nth(relay as usize) {
-                    *state = ON!();
-                    // led.export().map_err(|_| {OtaErr::SelectPinErr})?;
-                    // led.set_direction(Direction::Out).map_err(|_| {OtaErr::SetDirectionErr})?;
-                    // led.set_value(0).map_err(|_| {OtaErr::SetValueErr})?; 
-                }
-                Ok(())
+                self.drive_relay(relay as usize, true)
             }
 
             GpioIn::RelayOff{pin:relay} => {
                 log::info!("off");
-                if let Some((relay, state)) = self.io.iter_mut().nth(relay as usize) {
-                    *state = OFF!();
-                    // led.export().map_err(|_| {OtaErr::SelectPinErr})?;
-                    // led.set_direction(Direction::Out).map_err(|_| {OtaErr::SetDirectionErr})?;
-                    // led.set_value(0).map_err(|_| {OtaErr::SetValueErr})?; 
-                }
-                Ok(())
+                self.drive_relay(relay as usize, false)
             }
 
-            GpioIn::FanModeLv1 => {
-                log::info!("Fan mode 1");
-                // for pin in &self.fan {
-                //     pin.export().map_err(|_| {OtaErr::SelectPinErr})?;
-                //     pin.set_direction(Direction::Out).map_err(|_| {OtaErr::SetDirectionErr})?;
-                //     pin.set_value(0).map_err(|_| {OtaErr::SetValueErr})?; 
-                // }
-                Ok(())
-            }
-            
-            GpioIn::FanModeLv2 => {
-                log::info!("Fan mode 2");
-                // for (index, pin) in self.fan.iter().enumerate() {
-                //     pin.export().map_err(|_| {OtaErr::SelectPinErr})?;
-                //     pin.set_direction(Direction::Out).map_err(|_| {OtaErr::SetDirectionErr})?;
-
-                //     if index == 0 {
-                //         pin.set_value(0).map_err(|_| {OtaErr::SetValueErr})?;
-                //     }
-                //     else {
-                //         pin.set_value(1).map_err(|_| {OtaErr::SetValueErr})?; 
-                    
-                //     }
-                
-                // }
-                Ok(())
-            }
-            
-            GpioIn::FanModeLv3 => {
-                log::info!("Fan mode level 3");
-                // for (index, pin) in self.fan.iter().enumerate() {
-                //     pin.export().map_err(|_| {OtaErr::SelectPinErr})?;
-                //     pin.set_direction(Direction::Out).map_err(|_| {OtaErr::SetDirectionErr})?;
-                //     if index == 1 {
-                //         pin.set_value(0).map_err(|_| {OtaErr::SetValueErr})?;
-                //     }
-                //     else {
-                //         pin.set_value(1).map_err(|_| {OtaErr::SetValueErr})?; 
-                    
-                //     }    
-                // }
+            GpioIn::FanMode{level, pattern} => {
+                log::info!("Fan mode level {}", level);
+                if pattern.len() != self.fan.len() {
+                    log::warn!("fan pattern {:?} for {} pins", pattern, self.fan.len());
+                }
+                for (pin, value) in self.fan.iter().zip(pattern) {
+                    self.backend.set_value(pin, value)?;
+                }
                 Ok(())
             }
-            
         }
     }
+    // Write the relay level and read it back; the cached state only changes
+    // once the pin really moved.
+    fn drive_relay(&mut self, relay: usize, on: bool) -> Result<(), OtaErr> {
+        let (pin, state, polarity) = self.io.get_mut(relay).ok_or(OtaErr::SelectPinErr)?;
+        let level = polarity.level(on);
+
+        self.backend.set_value(pin, level)?;
+        let read = self.backend.get_value(pin)?;
+        if read != level {
+            log::error!("relay {} readback {} expected {}", relay, read, level);
+            return Err(OtaErr::ReadbackErr);
+        }
+        *state = if on { ON!() } else { OFF!() };
+        Ok(())
+    }
+
     pub async fn blink_parse(&mut self,pin:u64,fre_init :u16) -> Result<(),OtaErr>  {
-        if let Some((_ ,_, _, _, temp)) = self.leds.iter_mut().nth(pin as usize) { 
-            if *temp != fre_init as u16 {
-                *temp = fre_init as u16;
+        if let Some((_ ,_, _, _, temp)) = self.leds.get_mut(pin as usize) { 
+            if *temp != fre_init {
+                *temp = fre_init;
                 return Ok(());
             }
             else {
-                *temp = fre_init as u16;
+                *temp = fre_init;
                 return Err(OtaErr::RepeatErr);
             }
         }
-        return Err(OtaErr::RepeatErr);
+        Err(OtaErr::RepeatErr)
     }
 
-    pub async fn check_temp(&mut self) -> Result<u32, OtaErr>{
-        let mut file = File::open("bhien".to_string()).await.map_err(|_| {OtaErr::OpenFileErr})?;
-
-        let mut buffer = Vec::new();
+    pub fn get_value_led(&self) -> Vec<LedMode> {
+        self.leds
+            .iter()
+            .map(|(_, state, _, _, fre)| match *state {
+                ON!() => LedMode::On,
+                BLINK!() => LedMode::Blink { fre: *fre },
+                _ => LedMode::Off,
+            })
+            .collect()
+    }
 
-        file.read_to_end(&mut buffer).await.map_err(|_| {OtaErr::ReadFileErr})?;
-        
-        let content = String::from_utf8_lossy(&buffer);
+    // Take the relay state from the pin instead of driving it.
+    pub fn read_relay(&mut self, relay: usize) -> Result<bool, OtaErr> {
+        let (pin, state, polarity) = self.io.get_mut(relay).ok_or(OtaErr::SelectPinErr)?;
+        let on = self.backend.get_value(pin)? == polarity.level(true);
+        *state = if on { ON!() } else { OFF!() };
+        Ok(on)
+    }
 
-        let temp = content.trim().parse::<u32>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)).map_err(|_| {OtaErr::ConvertTempErr})?;
-        Ok(temp)
+    pub async fn get_value_relay(&mut self) -> Vec<bool> {
+        let mut states:Vec<bool> = Vec::new();
+        for (_, state, _) in &self.io {
+            states.push(*state == ON!());
+        }
+        states
     }
-    
-    pub async fn control_fan(&mut self, cpu_temperature:i32) {
-        let diff:i32 = (cpu_temperature as i32) - (self.last_cpu_temperature as i32);
-        //let mut temperature_level = TemperatureLevel::None;
-        if diff != 0  {
-            if cpu_temperature < 48 {
-                let _ = self.send(GpioIn::FanModeLv1).await;
-            } 
-
-            else if cpu_temperature <= 52 {
-                if diff < 0 {
-                    let _ = self.send(GpioIn::FanModeLv1).await;
+}
 
-                }
-                else { 
-                    let _ = self.send(GpioIn::FanModeLv2).await;
-                }
-            } 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::memory::MemoryBackend;
 
-            else if cpu_temperature <= 58 {
-                let _ = self.send(GpioIn::FanModeLv2).await;
-            } 
+    #[tokio::test]
+    async fn test_led_on_off_drive_backend() {
+        let backend = Arc::new(MemoryBackend::new());
+        let leds = vec![PinSpec::number(10), PinSpec::number(11)];
+        let mut gpio = GpioDriver::new(backend.clone(), leds, vec![], vec![]);
 
-            else if cpu_temperature <= 62 {
-                if diff < 0 {
-                    let _ = self.send(GpioIn::FanModeLv2).await;
+        assert!(backend.is_exported(&PinSpec::number(10)));
+        assert_eq!(backend.value(&PinSpec::number(10)), Some(OFF!()));
 
-                }
-                else { 
-                    let _ = self.send(GpioIn::FanModeLv3).await;
-                }
-            } 
-            
-            else {
-                let _ = self.send(GpioIn::FanModeLv3).await;
-            }
-        }
-        self.last_cpu_temperature = cpu_temperature as u32;
+        gpio.send(GpioIn::LedOn { pin: 1 }).await.unwrap();
+        assert_eq!(backend.value(&PinSpec::number(11)), Some(ON!()));
+        assert_eq!(backend.value(&PinSpec::number(10)), Some(OFF!()));
+
+        gpio.send(GpioIn::LedOff { pin: 1 }).await.unwrap();
+        assert_eq!(backend.value(&PinSpec::number(11)), Some(OFF!()));
     }
 
-    pub async fn get_value_relay(&mut self) -> Vec<bool> {
-        let mut states:Vec<bool> = Vec::new();
-        for (_, state) in &self.io {
-            if *state == ON!() {
-                states.push(true);
+    #[tokio::test]
+    async fn test_button_stops_on_broken_line() {
+        let backend = Arc::new(MemoryBackend::new());
+        let pin = PinSpec::number(14);
+        let mut button = ButtonDriver::new(backend.clone(), pin.clone(), Duration::from_millis(20), GestureConfig::default());
+        button.start().unwrap();
+        assert_eq!(backend.edge_listeners(&pin), 1);
+
+        // the error is logged once and the task ends instead of spinning on it
+        backend.fail(&pin);
+        timeout(Duration::from_millis(500), async {
+            while backend.edge_listeners(&pin) > 0 {
+                sleep(Duration::from_millis(5)).await;
             }
-            else {
-                states.push(false);
+        })
+        .await
+        .unwrap();
+        backend.drive(&pin, 0);
+        assert!(timeout(Duration::from_millis(60), button.recv()).await.is_err());
+    }
 
-            }
+    #[tokio::test]
+    async fn test_hold_progress_stays_full() {
+        let backend = Arc::new(MemoryBackend::new());
+        let leds = vec![PinSpec::number(10), PinSpec::number(11)];
+        let mut gpio = GpioDriver::new(backend.clone(), leds, vec![], vec![]);
+
+        for (stage, lit) in [(1, [ON!(), OFF!()]), (2, [ON!(), ON!()]), (3, [ON!(), ON!()]), (7, [ON!(), ON!()])] {
+            gpio.send(GpioIn::HoldProgress { stage }).await.unwrap();
+            assert_eq!([backend.value(&PinSpec::number(10)), backend.value(&PinSpec::number(11))], lit.map(Some), "stage {}", stage);
         }
-        return states;
     }
-}
 
+    #[tokio::test]
+    async fn test_led_blink_toggles() {
+        let backend = Arc::new(MemoryBackend::new());
+        let mut gpio = GpioDriver::new(backend.clone(), vec![PinSpec::number(10)], vec![], vec![]);
+
+        gpio.blink_parse(0, 100).await.unwrap();
+        gpio.send(GpioIn::LedBlink { pin: 0, blink: true, time: 0, fre: 100, get_tick: 1 }).await.unwrap();
+        assert_eq!(backend.value(&PinSpec::number(10)), Some(ON!()));
+        assert_eq!(gpio.get_value_led(), vec![LedMode::Blink { fre: 100 }]);
+
+        let next = timeout(Duration::from_millis(500), gpio.recv()).await.unwrap();
+        let Ok(GpioOut::LedBlinkContinue { led_pin, blink, time, fre }) = next else {
+            panic!("expected the blink to continue");
+        };
+        gpio.send(GpioIn::LedBlink { pin: led_pin, blink, time, fre, get_tick: 2 }).await.unwrap();
+        assert_eq!(backend.value(&PinSpec::number(10)), Some(OFF!()));
+    }
+
+    #[tokio::test]
+    async fn test_outputs_set_up_once_without_glitch() {
+        let backend = Arc::new(MemoryBackend::new());
+        let relay = PinSpec::number(20);
+        // left on (active low) by the previous run
+        backend.export(&relay).unwrap();
+        backend.set_direction(&relay, Direction::Low).unwrap();
+        let mut edges = backend.edge_stream(&relay, Edge::Both).unwrap();
+
+        let mut gpio = GpioDriver::new(backend.clone(), vec![], vec![(relay.clone(), Polarity::ActiveLow)], vec![]);
+        assert_eq!(gpio.read_relay(0), Ok(true));
+        gpio.send(GpioIn::RelayOn { pin: 0 }).await.unwrap();
+        gpio.send(GpioIn::RelayOff { pin: 0 }).await.unwrap();
+
+        // the only level change is the switch off
+        assert_eq!(edges.next().await, Some(Ok(1)));
+        assert!(timeout(Duration::from_millis(20), edges.next()).await.is_err());
+    }
 
+    #[tokio::test]
+    async fn test_relay_polarity_and_state() {
+        let backend = Arc::new(MemoryBackend::new());
+        let ios = vec![
+            (PinSpec::number(20), Polarity::ActiveLow),
+            (PinSpec::number(21), Polarity::ActiveHigh),
+        ];
+        let mut gpio = GpioDriver::new(backend.clone(), vec![], ios, vec![]);
+
+        gpio.send(GpioIn::RelayOn { pin: 0 }).await.unwrap();
+        gpio.send(GpioIn::RelayOn { pin: 1 }).await.unwrap();
+        assert_eq!(backend.value(&PinSpec::number(20)), Some(0));
+        assert_eq!(backend.value(&PinSpec::number(21)), Some(1));
+        assert_eq!(gpio.get_value_relay().await, vec![true, true]);
+
+        gpio.send(GpioIn::RelayOff { pin: 1 }).await.unwrap();
+        assert_eq!(backend.value(&PinSpec::number(21)), Some(0));
+        assert_eq!(gpio.get_value_relay().await, vec![true, false]);
+
+        assert_eq!(gpio.send(GpioIn::RelayOn { pin: 5 }).await, Err(OtaErr::SelectPinErr));
+    }
+
+    #[tokio::test]
+    async fn test_button_edges_are_debounced() {
+        let backend = Arc::new(MemoryBackend::new());
+        let pin = PinSpec::number(14);
+        let gestures = GestureConfig { hold_stage_ms: 0, ..GestureConfig::default() };
+        let mut button = ButtonDriver::new(backend.clone(), pin.clone(), Duration::from_millis(20), gestures);
+        button.start().unwrap();
+
+        // contact bounce settles low: one press
+        for value in [0, 1, 0, 1, 0] {
+            backend.drive(&pin, value);
+        }
+        assert!(matches!(button.recv().await, Ok(GpioOut::ButtonPressed)));
+
+        backend.drive(&pin, 1);
+        assert!(matches!(button.recv().await, Ok(GpioOut::ButtonReleased)));
+        assert!(matches!(button.recv().await, Ok(GpioOut::Gesture(Gesture::ShortPress))));
+
+        // a glitch shorter than the window that ends where it started is dropped
+        backend.drive(&pin, 0);
+        backend.drive(&pin, 1);
+        assert!(timeout(Duration::from_millis(60), button.recv()).await.is_err());
+    }
+}
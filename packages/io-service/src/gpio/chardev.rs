@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use gpiocdev::request::{Config, Request};
+use gpiocdev::line::{self as cdev_line, EdgeDetection, Value};
+use gpiocdev::tokio::AsyncRequest;
+use crate::error::OtaErr;
+use super::{Direction, Edge, EdgeStream, GpioBackend};
+use super::line::{self, Bias, Drive, Line, PinSpec};
+
+// GPIO v2 character device (/dev/gpiochipN). Every line is requested once on
+// export and the request is kept open, since releasing it resets the line.
+pub struct ChardevBackend {
+    consumer: String,
+    requests: Mutex<HashMap<Line, (Arc<AsyncRequest>, u32)>>,
+}
+
+impl ChardevBackend {
+    pub fn new(consumer: &str) -> Self {
+        ChardevBackend {
+            consumer: consumer.to_string(),
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn resolve(&self, pin: &PinSpec) -> Result<(PathBuf, u32), OtaErr> {
+        match &pin.line {
+            Line::Offset { chip, offset } => Ok((line::chip_path(chip), *offset)),
+            Line::Name(name) => {
+                let found = gpiocdev::find_named_line(name).ok_or_else(|| {
+                    log::error!("gpio line {:?} not found", name);
+                    OtaErr::SelectPinErr
+                })?;
+                Ok((found.chip, found.info.offset))
+            }
+            // global sysfs numbers do not map to a chip, Config::validate
+            // already rejects them
+            Line::Number(number) => {
+                log::error!("gpio line {}: the chardev backend needs gpiochipN:offset or a line name", number);
+                Err(OtaErr::SelectPinErr)
+            }
+        }
+    }
+
+    fn request(&self, pin: &PinSpec) -> Result<(Arc<AsyncRequest>, u32), OtaErr> {
+        let mut requests = self.requests.lock().unwrap();
+        if let Some((request, offset)) = requests.get(&pin.line) {
+            return Ok((request.clone(), *offset));
+        }
+
+        let (chip, offset) = self.resolve(pin)?;
+        let mut config = Config::default();
+        config.on_chip(chip).with_line(offset).as_is();
+        apply_line_config(&mut config, pin);
+
+        let request = Request::from_config(config)
+            .with_consumer(self.consumer.clone())
+            .request()
+            .map_err(|e| {
+                log::error!("request gpio line {}: {}", pin.line, e);
+                OtaErr::SelectPinErr
+            })?;
+        let request = Arc::new(AsyncRequest::new(request));
+        requests.insert(pin.line.clone(), (request.clone(), offset));
+        Ok((request, offset))
+    }
+
+    fn reconfigure<F>(&self, pin: &PinSpec, err: OtaErr, update: F) -> Result<(Arc<AsyncRequest>, u32), OtaErr>
+    where
+        F: FnOnce(&mut Config),
+    {
+        let (request, offset) = self.request(pin)?;
+        let mut config = sync(&request).config();
+        config.with_line(offset);
+        apply_line_config(&mut config, pin);
+        update(&mut config);
+        sync(&request).reconfigure(&config).map_err(|_| err)?;
+        Ok((request, offset))
+    }
+}
+
+fn sync(request: &AsyncRequest) -> &Request {
+    request.as_ref()
+}
+
+fn apply_line_config(config: &mut Config, pin: &PinSpec) {
+    if pin.config.active_low {
+        config.as_active_low();
+    }
+    if let Some(bias) = pin.config.bias {
+        config.with_bias(match bias {
+            Bias::PullUp => cdev_line::Bias::PullUp,
+            Bias::PullDown => cdev_line::Bias::PullDown,
+            Bias::Disabled => cdev_line::Bias::Disabled,
+        });
+    }
+    if let Some(drive) = pin.config.drive {
+        config.with_drive(match drive {
+            Drive::PushPull => cdev_line::Drive::PushPull,
+            Drive::OpenDrain => cdev_line::Drive::OpenDrain,
+            Drive::OpenSource => cdev_line::Drive::OpenSource,
+        });
+    }
+}
+
+impl GpioBackend for ChardevBackend {
+    fn export(&self, pin: &PinSpec) -> Result<(), OtaErr> {
+        self.request(pin).map(|_| ())
+    }
+
+    fn set_direction(&self, pin: &PinSpec, direction: Direction) -> Result<(), OtaErr> {
+        let (request, offset) = self.request(pin)?;
+        let current = sync(&request).line_config(offset).and_then(|c| c.direction);
+        let (wanted, value) = match direction {
+            Direction::In => (cdev_line::Direction::Input, None),
+            Direction::Out => (cdev_line::Direction::Output, None),
+            Direction::High => (cdev_line::Direction::Output, Some(Value::Active)),
+            Direction::Low => (cdev_line::Direction::Output, Some(Value::Inactive)),
+        };
+        // reconfiguring an output drops its value, so only do it on change
+        if current == Some(wanted) {
+            return match value {
+                Some(value) => sync(&request).set_value(offset, value).map_err(|_| OtaErr::SetDirectionErr),
+                None => Ok(()),
+            };
+        }
+        self.reconfigure(pin, OtaErr::SetDirectionErr, |config| {
+            match direction {
+                Direction::In => config.as_input(),
+                _ => config.as_output(value.unwrap_or(Value::Inactive)),
+            };
+        })
+        .map(|_| ())
+    }
+
+    fn get_value(&self, pin: &PinSpec) -> Result<u8, OtaErr> {
+        let (request, offset) = self.request(pin)?;
+        let value = sync(&request).value(offset).map_err(|_| OtaErr::GetValueErr)?;
+        Ok(value.into())
+    }
+
+    fn set_value(&self, pin: &PinSpec, value: u8) -> Result<(), OtaErr> {
+        let (request, offset) = self.request(pin)?;
+        sync(&request).set_value(offset, value.into()).map_err(|_| OtaErr::SetValueErr)
+    }
+
+    fn edge_stream(&self, pin: &PinSpec, edge: Edge) -> Result<EdgeStream, OtaErr> {
+        let (request, offset) = self.reconfigure(pin, OtaErr::SetEdgeErr, |config| {
+            config.as_input().with_edge_detection(match edge {
+                Edge::Rising => EdgeDetection::RisingEdge,
+                Edge::Falling => EdgeDetection::FallingEdge,
+                Edge::Both => EdgeDetection::BothEdges,
+            });
+        })?;
+
+        // a failed read means the line is gone: report it once and end the
+        // stream instead of failing again right away forever
+        let stream = futures::stream::unfold(Some(request), move |request| async move {
+            let request = request?;
+            match request.read_edge_event().await {
+                Ok(_) => {
+                    let value = sync(&request).value(offset).map(u8::from).map_err(|_| OtaErr::GetValueErr);
+                    Some((value, Some(request)))
+                }
+                Err(e) => {
+                    log::error!("gpio edge event on line {}: {}", offset, e);
+                    Some((Err(OtaErr::GetValueErr), None))
+                }
+            }
+        });
+        Ok(Box::pin(stream))
+    }
+}
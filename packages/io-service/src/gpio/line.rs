@@ -0,0 +1,170 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Line {
+    // legacy global sysfs number, e.g. "17"
+    Number(u64),
+    // "gpiochip0:17", "0:17" or "/dev/gpiochip0:17"
+    Offset { chip: String, offset: u32 },
+    // line name from the device tree, e.g. "RELAY_1"
+    Name(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bias {
+    PullUp,
+    PullDown,
+    Disabled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Drive {
+    PushPull,
+    OpenDrain,
+    OpenSource,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct LineConfig {
+    pub bias: Option<Bias>,
+    pub drive: Option<Drive>,
+    pub active_low: bool,
+}
+
+// A pin as given on the command line: a line plus optional "+flag" suffixes,
+// e.g. "gpiochip0:17+active-low+pull-up".
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PinSpec {
+    pub line: Line,
+    pub config: LineConfig,
+}
+
+impl PinSpec {
+    pub fn number(pin: u64) -> Self {
+        PinSpec {
+            line: Line::Number(pin),
+            config: LineConfig::default(),
+        }
+    }
+}
+
+pub fn chip_path(chip: &str) -> PathBuf {
+    if chip.starts_with('/') {
+        PathBuf::from(chip)
+    }
+    else if chip.chars().all(|c| c.is_ascii_digit()) {
+        PathBuf::from(format!("/dev/gpiochip{}", chip))
+    }
+    else {
+        PathBuf::from(format!("/dev/{}", chip))
+    }
+}
+
+impl FromStr for Line {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty pin".to_string());
+        }
+        if let Ok(number) = s.parse::<u64>() {
+            return Ok(Line::Number(number));
+        }
+        if let Some((chip, offset)) = s.rsplit_once(':') {
+            let offset = offset.parse::<u32>().map_err(|_| format!("invalid line offset in {:?}", s))?;
+            if chip.is_empty() {
+                return Err(format!("missing chip in {:?}", s));
+            }
+            return Ok(Line::Offset { chip: chip.to_string(), offset });
+        }
+        Ok(Line::Name(s.to_string()))
+    }
+}
+
+impl FromStr for PinSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split('+');
+        let line = parts.next().unwrap_or_default().parse::<Line>()?;
+        let mut config = LineConfig::default();
+
+        for flag in parts {
+            match flag {
+                "active-low" => config.active_low = true,
+                "pull-up" => config.bias = Some(Bias::PullUp),
+                "pull-down" => config.bias = Some(Bias::PullDown),
+                "bias-disabled" => config.bias = Some(Bias::Disabled),
+                "push-pull" => config.drive = Some(Drive::PushPull),
+                "open-drain" => config.drive = Some(Drive::OpenDrain),
+                "open-source" => config.drive = Some(Drive::OpenSource),
+                _ => return Err(format!("unknown pin flag {:?} in {:?}", flag, s)),
+            }
+        }
+        Ok(PinSpec { line, config })
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Number(number) => write!(f, "{}", number),
+            Line::Offset { chip, offset } => write!(f, "{}:{}", chip, offset),
+            Line::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl fmt::Display for PinSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.line)?;
+        if self.config.active_low {
+            write!(f, "+active-low")?;
+        }
+        match self.config.bias {
+            Some(Bias::PullUp) => write!(f, "+pull-up")?,
+            Some(Bias::PullDown) => write!(f, "+pull-down")?,
+            Some(Bias::Disabled) => write!(f, "+bias-disabled")?,
+            None => {}
+        }
+        match self.config.drive {
+            Some(Drive::PushPull) => write!(f, "+push-pull")?,
+            Some(Drive::OpenDrain) => write!(f, "+open-drain")?,
+            Some(Drive::OpenSource) => write!(f, "+open-source")?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_pin_spec() {
+        assert_eq!("17".parse::<PinSpec>().unwrap(), PinSpec::number(17));
+
+        let spec = "gpiochip1:5+active-low+pull-up".parse::<PinSpec>().unwrap();
+        assert_eq!(spec.line, Line::Offset { chip: "gpiochip1".to_string(), offset: 5 });
+        assert!(spec.config.active_low);
+        assert_eq!(spec.config.bias, Some(Bias::PullUp));
+        assert_eq!(spec.to_string(), "gpiochip1:5+active-low+pull-up");
+
+        let spec = "RELAY_1+open-drain".parse::<PinSpec>().unwrap();
+        assert_eq!(spec.line, Line::Name("RELAY_1".to_string()));
+        assert_eq!(spec.config.drive, Some(Drive::OpenDrain));
+
+        assert!("gpiochip0:x".parse::<PinSpec>().is_err());
+        assert!("17+slow".parse::<PinSpec>().is_err());
+    }
+
+    #[test]
+    fn test_chip_path() {
+        assert_eq!(chip_path("0"), PathBuf::from("/dev/gpiochip0"));
+        assert_eq!(chip_path("gpiochip2"), PathBuf::from("/dev/gpiochip2"));
+        assert_eq!(chip_path("/dev/gpiochip3"), PathBuf::from("/dev/gpiochip3"));
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+use crate::error::OtaErr;
+use super::{Direction, Edge, EdgeStream, GpioBackend};
+use super::line::{Line, PinSpec};
+
+struct MemoryPin {
+    exported: bool,
+    direction: Direction,
+    value: u8,
+    edges: broadcast::Sender<Result<u8, OtaErr>>,
+}
+
+impl MemoryPin {
+    fn new() -> Self {
+        let (edges, _) = broadcast::channel(16);
+        MemoryPin {
+            exported: false,
+            direction: Direction::In,
+            value: 1,
+            edges,
+        }
+    }
+}
+
+// In-memory pins for tests and simulation. Inputs are driven from the outside
+// with `drive`, outputs can be inspected with `value`.
+#[derive(Default)]
+pub struct MemoryBackend {
+    pins: Mutex<HashMap<Line, MemoryPin>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+
+    // Simulate an external level change, e.g. a button press.
+    pub fn drive(&self, pin: &PinSpec, value: u8) {
+        let mut pins = self.pins.lock().unwrap();
+        let entry = pins.entry(pin.line.clone()).or_insert_with(MemoryPin::new);
+        if entry.value != value {
+            entry.value = value;
+            let _ = entry.edges.send(Ok(value));
+        }
+    }
+
+    // Simulate a line that broke, e.g. its chip went away: the edge streams
+    // report the error and end.
+    pub fn fail(&self, pin: &PinSpec) {
+        let mut pins = self.pins.lock().unwrap();
+        let entry = pins.entry(pin.line.clone()).or_insert_with(MemoryPin::new);
+        let _ = entry.edges.send(Err(OtaErr::GetValueErr));
+    }
+
+    // How many edge streams are open on the pin.
+    pub fn edge_listeners(&self, pin: &PinSpec) -> usize {
+        self.pins.lock().unwrap().get(&pin.line).map_or(0, |p| p.edges.receiver_count())
+    }
+
+    pub fn value(&self, pin: &PinSpec) -> Option<u8> {
+        self.pins.lock().unwrap().get(&pin.line).map(|p| p.value)
+    }
+
+    pub fn is_exported(&self, pin: &PinSpec) -> bool {
+        self.pins.lock().unwrap().get(&pin.line).is_some_and(|p| p.exported)
+    }
+}
+
+impl GpioBackend for MemoryBackend {
+    fn export(&self, pin: &PinSpec) -> Result<(), OtaErr> {
+        let mut pins = self.pins.lock().unwrap();
+        pins.entry(pin.line.clone()).or_insert_with(MemoryPin::new).exported = true;
+        Ok(())
+    }
+
+    fn set_direction(&self, pin: &PinSpec, direction: Direction) -> Result<(), OtaErr> {
+        let mut pins = self.pins.lock().unwrap();
+        match pins.get_mut(&pin.line) {
+            Some(p) if p.exported => {
+                let value = match direction {
+                    Direction::High => Some(1),
+                    Direction::Low => Some(0),
+                    _ => None,
+                };
+                p.direction = if value.is_some() { Direction::Out } else { direction };
+                if let Some(value) = value.filter(|value| *value != p.value) {
+                    p.value = value;
+                    let _ = p.edges.send(Ok(value));
+                }
+                Ok(())
+            }
+            _ => Err(OtaErr::SetDirectionErr),
+        }
+    }
+
+    fn get_value(&self, pin: &PinSpec) -> Result<u8, OtaErr> {
+        let pins = self.pins.lock().unwrap();
+        match pins.get(&pin.line) {
+            Some(p) if p.exported => Ok(p.value),
+            _ => Err(OtaErr::GetValueErr),
+        }
+    }
+
+    fn set_value(&self, pin: &PinSpec, value: u8) -> Result<(), OtaErr> {
+        let mut pins = self.pins.lock().unwrap();
+        match pins.get_mut(&pin.line) {
+            Some(p) if p.exported && p.direction == Direction::Out => {
+                if p.value != value {
+                    p.value = value;
+                    let _ = p.edges.send(Ok(value));
+                }
+                Ok(())
+            }
+            _ => Err(OtaErr::SetValueErr),
+        }
+    }
+
+    fn edge_stream(&self, pin: &PinSpec, edge: Edge) -> Result<EdgeStream, OtaErr> {
+        let mut pins = self.pins.lock().unwrap();
+        let rx = pins.entry(pin.line.clone()).or_insert_with(MemoryPin::new).edges.subscribe();
+
+        // like the chardev backend, the stream ends after an error
+        let stream = futures::stream::unfold(Some(rx), move |rx| async move {
+            let mut rx = rx?;
+            loop {
+                match rx.recv().await {
+                    Ok(Err(e)) => return Some((Err(e), None)),
+                    Ok(Ok(value)) => {
+                        let wanted = match edge {
+                            Edge::Rising => value == 1,
+                            Edge::Falling => value == 0,
+                            Edge::Both => true,
+                        };
+                        if wanted {
+                            return Some((Ok(value), Some(rx)));
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(Box::pin(stream))
+    }
+}
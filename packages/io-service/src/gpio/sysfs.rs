@@ -0,0 +1,83 @@
+use sysfs_gpio::Pin;
+use futures::StreamExt;
+use crate::error::OtaErr;
+use super::{Direction, Edge, EdgeStream, GpioBackend};
+use super::line::{Line, PinSpec};
+
+// Legacy /sys/class/gpio interface, one Pin per call like the old driver code.
+// Only plain pin numbers can be addressed here, bias and drive are ignored.
+#[derive(Default)]
+pub struct SysfsBackend {}
+
+impl SysfsBackend {
+    pub fn new() -> Self {
+        SysfsBackend {}
+    }
+}
+
+impl From<Direction> for sysfs_gpio::Direction {
+    fn from(direction: Direction) -> Self {
+        match direction {
+            Direction::In => sysfs_gpio::Direction::In,
+            Direction::Out => sysfs_gpio::Direction::Out,
+            Direction::High => sysfs_gpio::Direction::High,
+            Direction::Low => sysfs_gpio::Direction::Low,
+        }
+    }
+}
+
+impl From<Edge> for sysfs_gpio::Edge {
+    fn from(edge: Edge) -> Self {
+        match edge {
+            Edge::Rising => sysfs_gpio::Edge::RisingEdge,
+            Edge::Falling => sysfs_gpio::Edge::FallingEdge,
+            Edge::Both => sysfs_gpio::Edge::BothEdges,
+        }
+    }
+}
+
+fn sysfs_pin(pin: &PinSpec) -> Result<Pin, OtaErr> {
+    match pin.line {
+        Line::Number(number) => Ok(Pin::new(number)),
+        _ => {
+            log::error!("sysfs backend can't address line {}", pin.line);
+            Err(OtaErr::SelectPinErr)
+        }
+    }
+}
+
+impl GpioBackend for SysfsBackend {
+    fn export(&self, pin: &PinSpec) -> Result<(), OtaErr> {
+        let sysfs = sysfs_pin(pin)?;
+        sysfs.export().map_err(|_| OtaErr::SelectPinErr)?;
+        if pin.config.active_low {
+            sysfs.set_active_low(true).map_err(|_| OtaErr::SelectPinErr)?;
+        }
+        Ok(())
+    }
+
+    fn set_direction(&self, pin: &PinSpec, direction: Direction) -> Result<(), OtaErr> {
+        // "high"/"low" are raw levels, unlike value they ignore active_low
+        let direction = match (direction, pin.config.active_low) {
+            (Direction::High, true) => Direction::Low,
+            (Direction::Low, true) => Direction::High,
+            (direction, _) => direction,
+        };
+        sysfs_pin(pin)?.set_direction(direction.into()).map_err(|_| OtaErr::SetDirectionErr)
+    }
+
+    fn get_value(&self, pin: &PinSpec) -> Result<u8, OtaErr> {
+        sysfs_pin(pin)?.get_value().map_err(|_| OtaErr::GetValueErr)
+    }
+
+    fn set_value(&self, pin: &PinSpec, value: u8) -> Result<(), OtaErr> {
+        sysfs_pin(pin)?.set_value(value).map_err(|_| OtaErr::SetValueErr)
+    }
+
+    fn edge_stream(&self, pin: &PinSpec, edge: Edge) -> Result<EdgeStream, OtaErr> {
+        let pin = sysfs_pin(pin)?;
+        pin.set_edge(edge.into()).map_err(|_| OtaErr::SetEdgeErr)?;
+        let stream = pin.get_value_stream().map_err(|_| OtaErr::SetEdgeErr)?;
+        Ok(Box::pin(stream.map(|value| value.map_err(|_| OtaErr::GetValueErr))))
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Where the device id used in the io-<id>-<n> hashes comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdSource {
+    // MAC of a network interface, /sys/class/net/<iface>/address
+    Interface,
+    // /etc/machine-id
+    MachineId,
+    // device tree serial-number
+    Serial,
+}
+
+impl FromStr for IdSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interface" => Ok(IdSource::Interface),
+            "machine-id" => Ok(IdSource::MachineId),
+            "serial" => Ok(IdSource::Serial),
+            _ => Err(format!("unknown id source {:?}, expected interface, machine-id or serial", s)),
+        }
+    }
+}
+
+impl fmt::Display for IdSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdSource::Interface => write!(f, "interface"),
+            IdSource::MachineId => write!(f, "machine-id"),
+            IdSource::Serial => write!(f, "serial"),
+        }
+    }
+}
+
+// "interface,machine-id,serial"
+pub fn parse_sources(s: &str) -> Result<Vec<IdSource>, String> {
+    s.split(',').map(str::trim).filter(|i| !i.is_empty()).map(str::parse).collect()
+}
+
+// Incoming relay commands are split on '-' (OtaLogic::parse_data_string), so
+// the id must not contain one.
+pub fn check_id(id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err("device id is empty".to_string());
+    }
+    if let Some(c) = id.chars().find(|c| !c.is_ascii_alphanumeric() && *c != '_') {
+        return Err(format!("device id {:?} contains {:?}, only letters, digits and '_' are allowed", id, c));
+    }
+    Ok(())
+}
+
+pub struct Identity {
+    // "/" on a device, a temp dir in tests
+    root: PathBuf,
+    interface: String,
+    sources: Vec<IdSource>,
+}
+
+impl Identity {
+    pub fn new(root: impl AsRef<Path>, interface: &str, sources: Vec<IdSource>) -> Self {
+        Identity {
+            root: root.as_ref().to_path_buf(),
+            interface: interface.to_string(),
+            sources,
+        }
+    }
+
+    fn file(&self, path: &str) -> Option<String> {
+        let content = std::fs::read(self.root.join(path)).ok()?;
+        // device tree strings are NUL terminated
+        let content = String::from_utf8_lossy(&content).trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string();
+        Some(content)
+    }
+
+    pub fn read(&self, source: IdSource) -> Option<String> {
+        match source {
+            IdSource::Interface => {
+                let mac = self.file(&format!("sys/class/net/{}/address", self.interface))?;
+                let hex: String = mac.chars().filter(|c| *c != ':').collect::<String>().to_lowercase();
+                if hex.len() != 12 || hex.chars().all(|c| c == '0') {
+                    return None;
+                }
+                Some(hex)
+            }
+            IdSource::MachineId => self.file("etc/machine-id"),
+            IdSource::Serial => self
+                .file("proc/device-tree/serial-number")
+                .or_else(|| self.file("sys/firmware/devicetree/base/serial-number")),
+        }
+    }
+
+    // First source in order that gives a usable id.
+    pub fn resolve(&self) -> Result<(IdSource, String), String> {
+        for source in &self.sources {
+            match self.read(*source) {
+                Some(id) => match check_id(&id) {
+                    Ok(()) => return Ok((*source, id)),
+                    Err(e) => log::warn!("id from {}: {}", source, e),
+                },
+                None => log::debug!("no device id from {}", source),
+            }
+        }
+        let sources: Vec<String> = self.sources.iter().map(|s| s.to_string()).collect();
+        Err(format!("no usable device id from [{}], set identity.id_mac", sources.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_priority() {
+        let root = tempfile::tempdir().unwrap();
+        let all = parse_sources("interface,machine-id,serial").unwrap();
+
+        assert!(Identity::new(root.path(), "eth0", all.clone()).resolve().is_err());
+
+        write(root.path(), "proc/device-tree/serial-number", "1000000012ab34cd\0");
+        assert_eq!(Identity::new(root.path(), "eth0", all.clone()).resolve(), Ok((IdSource::Serial, "1000000012ab34cd".to_string())));
+
+        // a '-' in the machine id makes it unusable, the serial still wins
+        write(root.path(), "etc/machine-id", "3f2a-9c\n");
+        assert_eq!(Identity::new(root.path(), "eth0", all.clone()).resolve().unwrap().0, IdSource::Serial);
+
+        write(root.path(), "etc/machine-id", "3f2a9c0d5e6f\n");
+        assert_eq!(Identity::new(root.path(), "eth0", all.clone()).resolve().unwrap().0, IdSource::MachineId);
+
+        write(root.path(), "sys/class/net/eth0/address", "00:00:00:00:00:00\n");
+        assert_eq!(Identity::new(root.path(), "eth0", all.clone()).resolve().unwrap().0, IdSource::MachineId);
+
+        write(root.path(), "sys/class/net/eth0/address", "8E:A4:37:69:E4:D6\n");
+        assert_eq!(Identity::new(root.path(), "eth0", all).resolve(), Ok((IdSource::Interface, "8ea43769e4d6".to_string())));
+    }
+
+    #[test]
+    fn test_check_id() {
+        assert!(check_id("Mi8ea43769e4d6Qb").is_ok());
+        assert!(check_id("").is_err());
+        assert!(check_id("io-1").is_err());
+        assert!(parse_sources("interface,uuid").is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use tokio::time::{Duration, Instant};
+
+// How a group keeps its relays from being on together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    // turning one on turns the others off
+    Exclusive,
+    // turning one on while another is on is refused
+    Forbid,
+    // like exclusive, but the relay only goes on once the others have been
+    // off for this long
+    DeadTime(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub relays: Vec<usize>,
+    pub mode: Mode,
+}
+
+// What turning a relay on takes.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    // switch `off` off first, then the relay, after `wait_ms` when set
+    Allow { off: Vec<usize>, wait_ms: Option<u64> },
+    // refused because this relay of the group is on
+    Forbid(usize),
+}
+
+// Groups from gpio.relay_interlock, ';'-separated RELAYS:MODE with the relays
+// joined by '+', e.g. "0+1:exclusive;2+3:dead-time=500".
+#[derive(Debug, Default)]
+pub struct Interlocks {
+    pub groups: Vec<Group>,
+    // when each relay was last switched off, for dead-time
+    last_off: BTreeMap<usize, Instant>,
+}
+
+impl Interlocks {
+    pub fn group(&self, relay: usize) -> Option<&Group> {
+        self.groups.iter().find(|group| group.relays.contains(&relay))
+    }
+
+    // Switching off is always allowed; `states` are the relays as they are now.
+    pub fn check(&self, relay: usize, on: bool, states: &[bool], now: Instant) -> Verdict {
+        let group = match self.group(relay) {
+            Some(group) if on => group,
+            _ => return Verdict::Allow { off: Vec::new(), wait_ms: None },
+        };
+        let others: Vec<usize> = group.relays.iter().copied().filter(|other| *other != relay).collect();
+        let on_now: Vec<usize> = others.iter().copied().filter(|other| states.get(*other).copied().unwrap_or(false)).collect();
+        match group.mode {
+            Mode::Exclusive => Verdict::Allow { off: on_now, wait_ms: None },
+            Mode::Forbid => match on_now.first() {
+                Some(other) => Verdict::Forbid(*other),
+                None => Verdict::Allow { off: Vec::new(), wait_ms: None },
+            },
+            Mode::DeadTime(dead_ms) => {
+                let gap = Duration::from_millis(dead_ms);
+                // the ones going off now need the whole gap
+                let wait = match on_now.is_empty() {
+                    false => gap,
+                    true => others
+                        .iter()
+                        .filter_map(|other| self.last_off.get(other))
+                        .map(|off| (*off + gap).saturating_duration_since(now))
+                        .max()
+                        .unwrap_or_default(),
+                };
+                let wait_ms = Some(wait.as_millis() as u64).filter(|ms| *ms > 0);
+                Verdict::Allow { off: on_now, wait_ms }
+            }
+        }
+    }
+
+    // Turn off the relays that break their group in `states`, going through
+    // them in `order` so the earlier ones win; the ones turned off are
+    // returned.
+    pub fn settle(&self, states: &mut [bool], order: &[usize], now: Instant) -> Vec<usize> {
+        let mut kept = vec![false; states.len()];
+        let mut off = Vec::new();
+        for &relay in order {
+            if !states[relay] {
+                continue;
+            }
+            match self.check(relay, true, &kept, now) {
+                Verdict::Allow { off, wait_ms: None } if off.is_empty() => kept[relay] = true,
+                _ => {
+                    states[relay] = false;
+                    off.push(relay);
+                }
+            }
+        }
+        off
+    }
+
+    // Called for every relay that was driven.
+    pub fn switched(&mut self, relay: usize, on: bool, now: Instant) {
+        match on {
+            true => self.last_off.remove(&relay),
+            false => self.last_off.insert(relay, now),
+        };
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exclusive" => Ok(Mode::Exclusive),
+            "forbid" => Ok(Mode::Forbid),
+            _ => match s.strip_prefix("dead-time=").map(str::parse::<u64>) {
+                Some(Ok(ms)) if ms > 0 => Ok(Mode::DeadTime(ms)),
+                _ => Err(format!("invalid interlock mode {:?}, expected exclusive, forbid or dead-time=MS", s)),
+            },
+        }
+    }
+}
+
+impl FromStr for Interlocks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut interlocks = Interlocks::default();
+        for item in s.split(';').map(str::trim).filter(|i| !i.is_empty()) {
+            let (relays, mode) = item.split_once(':').ok_or_else(|| format!("interlock {:?} is not RELAYS:MODE", item))?;
+            let mut group = Group { relays: Vec::new(), mode: mode.trim().parse()? };
+            for relay in relays.split('+').map(str::trim) {
+                let relay = relay.parse::<usize>().map_err(|_| format!("interlock {:?}: {:?} is not a relay index", item, relay))?;
+                if group.relays.contains(&relay) || interlocks.group(relay).is_some() {
+                    return Err(format!("interlock {:?}: relay {} is listed twice", item, relay));
+                }
+                group.relays.push(relay);
+            }
+            if group.relays.len() < 2 {
+                return Err(format!("interlock {:?}: a group needs at least two relays", item));
+            }
+            interlocks.groups.push(group);
+        }
+        Ok(interlocks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_interlocks() {
+        let mut interlocks: Interlocks = "0+1:exclusive; 2+3:forbid; 4+5+6:dead-time=500".parse().unwrap();
+        assert_eq!(interlocks.groups[2], Group { relays: vec![4, 5, 6], mode: Mode::DeadTime(500) });
+        for bad in ["0+1", "0:forbid", "0+1:lock", "0+1:dead-time=0", "0+x:forbid", "0+1:forbid;1+2:forbid"] {
+            assert!(bad.parse::<Interlocks>().is_err(), "{}", bad);
+        }
+
+        let now = Instant::now();
+        let states = [true, false, false, true, false, true, false, true];
+        assert_eq!(interlocks.check(1, true, &states, now), Verdict::Allow { off: vec![0], wait_ms: None });
+        assert_eq!(interlocks.check(1, false, &states, now), Verdict::Allow { off: vec![], wait_ms: None });
+        assert_eq!(interlocks.check(2, true, &states, now), Verdict::Forbid(3));
+        assert_eq!(interlocks.check(7, true, &states, now), Verdict::Allow { off: vec![], wait_ms: None });
+        assert_eq!(interlocks.check(4, true, &states, now), Verdict::Allow { off: vec![5], wait_ms: Some(500) });
+
+        // 5 went off 200 ms ago, 6 may go on in 300 ms
+        interlocks.switched(5, false, now);
+        let later = now + Duration::from_millis(200);
+        let states = [false; 8];
+        assert_eq!(interlocks.check(6, true, &states, later), Verdict::Allow { off: vec![], wait_ms: Some(300) });
+        assert_eq!(interlocks.check(6, true, &states, later + Duration::from_millis(300)), Verdict::Allow { off: vec![], wait_ms: None });
+        // 5 itself is not held back
+        assert_eq!(interlocks.check(5, true, &states, later), Verdict::Allow { off: vec![], wait_ms: None });
+    }
+
+    #[test]
+    fn test_settle() {
+        let interlocks: Interlocks = "0+1:exclusive; 2+3:forbid".parse().unwrap();
+        let now = Instant::now();
+        let mut states = [true, true, true, true, true];
+        assert_eq!(interlocks.settle(&mut states, &[1, 3, 0, 2, 4], now), vec![0, 2]);
+        assert_eq!(states, [false, true, false, true, true]);
+
+        let mut states = [true, false, false, true];
+        assert_eq!(interlocks.settle(&mut states, &[0, 1, 2, 3], now), Vec::<usize>::new());
+    }
+}
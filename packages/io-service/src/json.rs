@@ -1,13 +1,104 @@
 extern crate serde_json;
-use serde_json::{Value, json};
+use serde_json::Value;
 use rand::Rng;
+use crate::error::OtaErr;
+use crate::error::ProtocolErr;
+use crate::transport::RelayResult;
+use crate::protocol::{ActionResult, Rejected, ControlSource, DeviceConfig, DeviceState, Empty, Envelope, Execution, LedData, Params, Presence, Request, RequestData, Status, Sync, TimerState, Trait};
 
 pub enum JsonIn {
-    StatusConvert{json_init: Value , pin:Vec<(bool,String)>},
-    SyncConvert{status: Vec<bool>, mac_id: String},
+    StatusConvert{json_init: Request , pin:Vec<(bool,String)>},
+    RelayStatusConvert{json_init: Request, mac_id: String, relays: Vec<RelayResult>},
+    // `timers` in the same order as `status`, missing ones are none
+    SyncConvert{status: Vec<bool>, timers: Vec<Option<TimerState>>, mac_id: String},
     KeepAlive,
+    CommandConvert{cmd: String},
+    EventConvert{action: String, result: Result<(), OtaErr>},
 }
 
+impl JsonIn {
+    // The status answering a relay command: StatusConvert when every relay
+    // simply took it, RelayStatusConvert with errors and timers otherwise.
+    pub fn relay_status(json_init: Request, mac_id: &str, relays: Vec<RelayResult>) -> JsonIn {
+        if relays.iter().any(|r| r.result.is_err() || r.timer.is_some()) {
+            return JsonIn::RelayStatusConvert{json_init, mac_id: mac_id.to_string(), relays};
+        }
+        let pin = relays.iter().map(|r| (r.on, relay_hash(mac_id, r))).collect();
+        JsonIn::StatusConvert{json_init, pin}
+    }
+}
+
+// io-<id>-<n>, or the hash as it came when it named no relay.
+fn relay_hash(mac_id: &str, relay: &RelayResult) -> String {
+    relay.hash.clone().unwrap_or_else(|| format!("io-{}-{}", mac_id, relay.relay))
+}
+
+// Answer to `json_init`: same control_source and reqid.
+pub fn status_value(json_init: &Request, pin: &[(bool, String)]) -> Status {
+    let data = pin.iter().map(|(status, hash)| DeviceState::new(hash, *status)).collect();
+    let mut json_status = Status::new("status", "devices", data);
+    json_status.control_source = json_init.control_source.clone();
+    json_status.reqid = json_init.reqid.clone();
+    json_status
+}
+
+// Retained on the presence topic: "online" after every connect, "offline"
+// on shutdown or as the broker-sent last will.
+pub fn presence_value(online: bool, mac_id: &str) -> Envelope<Presence> {
+    let state = if online { "online" } else { "offline" };
+    Envelope::new("presence", "presence", vec![Presence { mac: mac_id.to_string(), state: state.to_string() }])
+}
+
+// A relay "set" as the cloud sends it, for commands that come from a local API.
+pub fn relay_command_value(mac_id: &str, relay: usize, on: bool, reqid: &str, source: &str) -> Request {
+    let mut request = Request::new("set", "devices", vec![RequestData::Hash(format!("io-{}-{}", mac_id, relay))]);
+    request.objects[0].execution = Some(Execution { command: "OnOff".to_string(), params: Params { on: Some(on), ..Default::default() } });
+    request.control_source = Some(ControlSource { kind: source.to_string(), ..Default::default() });
+    request.reqid = reqid.to_string();
+    request.source = source.to_string();
+    request
+}
+
+// A led "set" addressed by index, understood next to the event codes.
+pub fn led_command_value(led: usize, mode: &str, fre: Option<u16>, reqid: &str, source: &str) -> Request {
+    let data = RequestData::Led(LedData { led: led as u64, mode: mode.to_string(), fre });
+    let mut request = Request::new("set", "led", vec![data]);
+    request.reqid = reqid.to_string();
+    request.source = source.to_string();
+    request
+}
+
+// A "get"; `kind` is "devices" for the relays or "temperature".
+pub fn get_value(kind: &str, reqid: &str, source: &str) -> Request {
+    let mut request = Request::new("get", kind, Vec::new());
+    request.reqid = reqid.to_string();
+    request.source = source.to_string();
+    request
+}
+
+// Direct answer to a local (socket) request: "ack", or "error" with the reason.
+pub fn reply_value(reqid: &str, error: Option<&str>) -> Empty {
+    Empty {
+        cmd: if error.is_some() { "error" } else { "ack" }.to_string(),
+        reqid: reqid.to_string(),
+        source: crate::protocol::SOURCE.to_string(),
+        error: error.map(String::from),
+        ..Default::default()
+    }
+}
+
+// Published on topics.error for a command that was not acted on.
+pub fn error_value(reqid: &str, topic: &str, err: &ProtocolErr) -> Envelope<Rejected> {
+    let mut reply = Envelope::new("error", "error", vec![Rejected { reason: err.reason().to_string(), topic: topic.to_string() }]);
+    reply.reqid = reqid.to_string();
+    reply.error = Some(err.to_string());
+    reply
+}
+
+// Streamed to local subscribers; `kind` is relay, button or temperature.
+pub fn local_event_value(kind: &str, data: Value) -> Empty {
+    Empty::new("event", kind, vec![data])
+}
 
 pub struct JsonDriver {
 
@@ -26,145 +117,85 @@ impl JsonDriver {
     pub async fn convert(&mut self , type_res:JsonIn) -> (String, String) {
         match type_res {
             JsonIn::StatusConvert{json_init,pin} => {
-                let json_str = r#"{
-                    "cmd": "status",
-                    "control_source": {
-                        "id": "" ,
-                        "previous_control_reqid": "",
-                        "type": "app"
-                    },
-                    "objects": [{
-                        "bridge_key": "io",
-                        "data": [],
-                        "type": "devices"
-                    }],
-                    "reqid": "",
-                    "source": "io"
-                }"#;
-                        
-               // get control source  
-                let mut json_status: Value = serde_json::from_str(json_str).unwrap();
-                let control_source = json_init["control_source"].clone();    
-                json_status["control_source"] = control_source;
-        
-                // get reqid 
-                let reqid = json_init["reqid"].clone();
-                json_status["reqid"] = reqid;
-                
-                // get data 
-                let objects = json_status["objects"].as_array_mut().unwrap();
-
-                // Truy cập vào mảng "data" của phần tử đầu tiên trong "objects"
-                let data = objects[0]["data"].as_array_mut().unwrap();
-
-                for (status , hash) in &pin {
-                    data.push(json!({
-                        "hash": hash.to_string(),
-                        "states": {
-                            "OnOff": {"on": status }
-                        }
-                    }));
+                let json_status = status_value(&json_init, &pin);
+                (json_status.to_string(),"".to_string())
+            }
+            JsonIn::RelayStatusConvert{json_init, mac_id, relays} => {
+                // same shape as a status, the state is what the relay really
+                // holds, "error" says why the command was not applied and
+                // "timer" what is still to come
+                let pin: Vec<(bool, String)> = relays.iter().map(|r| (r.on, relay_hash(&mac_id, r))).collect();
+                let mut json_status = status_value(&json_init, &pin);
+                for (item, r) in json_status.objects[0].data.iter_mut().zip(&relays) {
+                    item.error = r.result.as_ref().err().map(|err| err.reason().to_string());
+                    item.timer = r.timer;
                 }
 
                 (json_status.to_string(),"".to_string())
             }
-            JsonIn::SyncConvert{status, mac_id} => {
-
-                let json_str = r#"
-                {
-                    "cmd": "sync",
-                    "objects": [
-                        {
-                            "bridge_key": "io",
-                            "data":[
-                            ],
-                            "type": "devices_local"
-                        }
-                    ],
-                    "reqid": "",
-                    "source": "io"
-                }"#;
-                let mut json_config: Value = serde_json::from_str(json_str).unwrap();
-
-                let mut json_status = json!({
-                    "cmd": "status",
-                    "objects": [
-                        {
-                            "bridge_key": "io",
-                            "data": [
-                            ],
-                            "type": "devices"
-                        }
-                    ],
-                    "reqid": "4YmPnprQ2DWR1D9",
-                    "source": "io"
-                });
-
-                // get data 
-                let objects_cf = json_config["objects"].as_array_mut().unwrap();
-
-                // Truy cập vào mảng "data" của phần tử đầu tiên trong "objects"
-                let data_cf = objects_cf[0]["data"].as_array_mut().unwrap();
-
-                // get data 
-                let objects_st = json_status["objects"].as_array_mut().unwrap();
-
-                // Truy cập vào mảng "data" của phần tử đầu tiên trong "objects"
-                let data_st = objects_st[0]["data"].as_array_mut().unwrap();
-
+            JsonIn::SyncConvert{status, timers, mac_id} => {
+                let mut data_cf = Vec::new();
+                let mut data_st = Vec::new();
                 for (index, value) in status.iter().enumerate() {
-                    let hash = format!("io-{}-{}", mac_id, index); 
-
-                    data_cf.push(json!({
-                        "bridge_key": "io",
-                        "hash": hash.to_string(),
-                        "isDefault": true,
-                        "mac": mac_id.to_string(),
-                        "macdev": mac_id.to_string(),
-                        "traits": [
-                            {
-                                "is_main": *value,
-                                "name": "OnOff"
-                            }
-                        ],
-                        "type": "SWITCH"
-                    }));
-
-                    data_st.push(json!({
-                        "hash": hash.to_string(),
-                        "states": {
-                            "OnOff": {
-                                "on":*value
-                            }
-                        }
-                    }));
-
+                    let hash = format!("io-{}-{}", mac_id, index);
+
+                    data_cf.push(DeviceConfig {
+                        bridge_key: crate::protocol::BRIDGE_KEY.to_string(),
+                        hash: hash.clone(),
+                        is_default: true,
+                        mac: mac_id.clone(),
+                        macdev: mac_id.clone(),
+                        traits: vec![Trait { is_main: *value, name: "OnOff".to_string() }],
+                        kind: "SWITCH".to_string(),
+                    });
+                    let mut state = DeviceState::new(&hash, *value);
+                    state.timer = timers.get(index).copied().flatten();
+                    data_st.push(state);
                 }
-                json_status["reqid"] = json!(self.get_reqid().await);
-                json_config["reqid"] = json!(self.get_reqid().await);
-                
 
-                return (json_config.to_string() , json_status.to_string());
-                
+                let mut json_config = Sync::new("sync", "devices_local", data_cf);
+                let mut json_status = Status::new("status", "devices", data_st);
+                json_status.reqid = self.get_reqid().await;
+                json_config.reqid = self.get_reqid().await;
+
+                (json_config.to_string() , json_status.to_string())
+            }
+            JsonIn::CommandConvert{cmd} => {
+                let mut json_cmd = Empty::new(&cmd, "button", Vec::new());
+                json_cmd.reqid = self.get_reqid().await;
+
+                (json_cmd.to_string(), "".to_string())
+            }
+            JsonIn::EventConvert{action, result} => {
+                let data = ActionResult { action, ok: result.is_ok(), error: result.err().map(|err| err.reason().to_string()) };
+                let mut json_event = Envelope::new("event", "button", vec![data]);
+                json_event.reqid = self.get_reqid().await;
+
+                (json_event.to_string(), "".to_string())
             }
             JsonIn::KeepAlive => {
-                let mut json_ka = json!({
-                    "cmd": "status",
-                    "objects": [
-                        {
-                            "bridge_key": "io",
-                            "data": [],
-                            "type": "keepalive"
-                        }
-                    ],
-                    "reqid": "",
-                    "source": "io"
-                });
-
-                json_ka["reqid"] = json!(self.get_reqid().await);
-
-                return (json_ka.to_string(), "".to_string());
+                let mut json_ka = Empty::new("status", "keepalive", Vec::new());
+                json_ka.reqid = self.get_reqid().await;
+
+                (json_ka.to_string(), "".to_string())
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sync_carries_timers() {
+        let mut json = JsonDriver {};
+        let timers = vec![None, Some(TimerState { on: false, in_ms: 4_000 })];
+        let (_, status) = json.convert(JsonIn::SyncConvert{status: vec![false, true], timers, mac_id: "AB12".to_string()}).await;
+        let status: Value = serde_json::from_str(&status).unwrap();
+        let data = &status["objects"][0]["data"];
+        assert_eq!(data[0].get("timer"), None);
+        assert_eq!(data[1]["hash"], "io-AB12-1");
+        assert_eq!(data[1]["timer"], serde_json::json!({"on": false, "in_ms": 4000}));
+    }
+}
@@ -0,0 +1,16 @@
+pub mod system_intergration;
+pub mod logic;
+pub mod transport;
+pub mod error;
+pub mod gpio;
+pub mod json;
+pub mod protocol;
+pub mod fan;
+pub mod thermal;
+pub mod gesture;
+pub mod action;
+pub mod config;
+pub mod identity;
+pub mod relay_state;
+pub mod relay_timer;
+pub mod interlock;
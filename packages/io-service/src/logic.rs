@@ -1,8 +1,10 @@
 use std::collections::VecDeque;
 use crate::error::OtaErr;
-use crate::transport::TransportOut;
+use crate::transport::{LedMode, TransportOut};
 use crate::gpio::GpioOut;
-use serde_json::Value;
+use crate::gesture::{Gesture, GestureMap};
+use crate::action::ActionOut;
+use crate::protocol::{ControlSource, RelayCommand, Request, RequestData};
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum DeviceOs {
@@ -30,10 +32,6 @@ macro_rules! GET {
     () => { "get" };
 }
 
-macro_rules! STATUS {
-    () => { "status" };
-}
-
 
 
 
@@ -60,7 +58,7 @@ macro_rules! EventCodeAi {
 pub enum GpioLogicIn { 
     Transport(Result<TransportOut, OtaErr>),
     Gpio(Result<GpioOut, OtaErr>),
-    
+    Action(ActionOut),
 }
 
 #[derive(Debug,Clone)]
@@ -74,45 +72,64 @@ pub enum GpioLogicOut {
     LedBlinkEvent{led_pin:u64, blink:bool ,time:u16, fre:u16},
     LedBlinkContinueEvent{led_pin:u64, blink:bool, time: u16, fre: u16},
 
-    RelayOnEvent{relay: usize, json_init: Value},
-    RelayOffEvent{relay: usize, json_init: Value},
+    // in order, answered with one status to `json_init`; a hash that names
+    // no relay is kept as Err(hash) so the status can reject it
+    RelayEvent{relays: Vec<(Result<usize, String>, RelayCommand)>, json_init: Request},
+    // a relay timer is due
+    RelayTimerEvent,
 
     ConfigRelayEvent,
 
     StopEvent,
-    ButtonBlinkEvent,
+    HoldProgressEvent{stage: u32},
     ReturnState,
+    ButtonActionEvent{action: String},
+    ActionDoneEvent{action: String, result: Result<(), OtaErr>},
 
     CheckTempCpuEvent,
 }
 
 
 
+fn led_event(led_pin: u64, mode: LedMode) -> GpioLogicOut {
+    match mode {
+        LedMode::On => GpioLogicOut::LedOnEvent{led_pin},
+        LedMode::Off => GpioLogicOut::LedOffEvent{led_pin},
+        LedMode::Blink{fre} => GpioLogicOut::LedBlinkEvent{led_pin, blink: true, time: 0, fre},
+    }
+}
+
 pub struct OtaLogic {
     pub outputs: VecDeque<GpioLogicOut>,
     pub device: DeviceOs,
     pub id_mac: String,
     pub tick :u64,
+    pub gestures: GestureMap,
+    pub network_led: Option<u64>,
+    hold_stage: u32,
 }
 
 impl OtaLogic {
-    pub fn new(device: DeviceOs, mac:String) -> Self {
+    pub fn new(device: DeviceOs, mac:String, gestures: GestureMap, network_led: Option<u64>) -> Self {
         let outputs = std::iter::once(GpioLogicOut::None).collect();
         OtaLogic {
-            outputs: outputs,
-            device: device,
+            outputs,
+            device,
             id_mac: mac,
             tick: 0,
+            gestures,
+            network_led,
+            hold_stage: 0,
         }
     }
 
 
 
-    fn parse_data_string(&mut self, data: &str) -> (String, usize) {
+    fn parse_data_string(&mut self, data: &str) -> (String, Option<usize>) {
         // Tách id... và value từ chuỗi data
         let parts: Vec<&str> = data.split("-").collect();
         let device_id = parts.get(1).map_or("", |&x| x);
-        let led_index: usize = parts.get(2).map_or("0", |&x| x).parse().unwrap_or(0);
+        let led_index = parts.get(2).and_then(|x| x.parse::<usize>().ok());
     
         (device_id.to_string(), led_index)
     }
@@ -201,62 +218,80 @@ impl OtaLogic {
         }
     }
 
-    fn relay_handle(&mut self, parsed_json:Value) -> GpioLogicOut{
+    fn relay_handle(&mut self, parsed_json:Request) -> GpioLogicOut{
         log::info!("Relay incoming");
-        let data_value = parsed_json["objects"][0]["data"][0].as_str().unwrap_or_default();
-        let (device_id, relay) = self.parse_data_string(data_value);
-        let value = parsed_json["objects"][0]["execution"]["params"]["on"].as_bool().unwrap_or(false);
-        if self.id_mac == device_id {
-            if value == true {
-                log::info!("Relay {} value is true",relay);
-                return GpioLogicOut::RelayOnEvent{relay, json_init:parsed_json};
+        let mut relays = Vec::new();
+        for (data_value, command) in parsed_json.changes() {
+            let (device_id, relay) = self.parse_data_string(data_value);
+            // hashes of other devices share the topic
+            if self.id_mac != device_id {
+                continue;
             }
-            else {
-                log::info!("Relay {} value is false",relay);
-                return GpioLogicOut::RelayOffEvent{relay, json_init:parsed_json};
+            match relay {
+                Some(relay) => {
+                    log::info!("Relay {} command {:?}", relay, command);
+                    relays.push((Ok(relay), command));
+                }
+                None => {
+                    log::warn!("Relay hash {:?} names no relay", data_value);
+                    relays.push((Err(data_value.to_string()), command));
+                }
             }
         }
-        return GpioLogicOut::None;
+        if relays.is_empty() {
+            return GpioLogicOut::None;
+        }
+        GpioLogicOut::RelayEvent{relays, json_init:parsed_json}
     }
 
 
-    fn led_handle(&mut self, parsed_json:Value, device: DeviceOs) -> GpioLogicOut {
+    fn led_handle(&mut self, parsed_json:Request, device: DeviceOs) -> GpioLogicOut {
         log::info!("Led incoming");  
-        if let Some(objects) = parsed_json.get("objects").and_then(Value::as_array) {
-            for obj in objects {
-                if let Some(data_array) = obj.get("data").and_then(Value::as_array) {
-                    for data_obj in data_array {
-                        if let Some(event_code) = data_obj.get("event_code").and_then(Value::as_str) {
-                            // Ở đây bạn có thể làm bất cứ điều gì với event_code, ví dụ:
-                            log::info!("Event Code: {}", event_code);
-                            let event_code = event_code.parse::<u32>().unwrap();     
-                            if device == DeviceOs::Hc {   
-                                let mut pin = 0; 
-                                pin = event_code / 10;
-                                let state = event_code % 10;
-                        
-                                let mut event = GpioLogicOut::StopEvent;
-                                log::info!("Pin: {}, state:{}", pin, state);
-                                if pin < 2 {
-                                    return event;
-                                }
-                                pin = pin - 2;
-                                
-                                match state {
-                                    ON!() => event =  GpioLogicOut::LedOnEvent { led_pin: pin as u64 }, 
-                                    OFF!() => event = GpioLogicOut::LedOffEvent {led_pin:pin as u64}, 
-                                    BLINK!()=> event=GpioLogicOut::LedBlinkEvent {led_pin:pin as u64, blink:false, time:20, fre: 1},
-                                    _ => {}
-                                }
-                                return event;
-                            }   
-                            // device ai
-                            else {
-                                return self.parse_led_ai(event_code);
-                            }   
-                        }
+        for data_obj in parsed_json.objects.iter().flat_map(|obj| &obj.data) {
+            // direct form from local tools: {"led": 1, "mode": "blink", "fre": 500}
+            if let RequestData::Led(data) = data_obj {
+                return match data.mode.parse::<LedMode>() {
+                    Ok(LedMode::Blink{fre}) => led_event(data.led, LedMode::Blink{fre: data.fre.unwrap_or(fre)}),
+                    Ok(mode) => led_event(data.led, mode),
+                    Err(e) => {
+                        log::warn!("led {}: {}", data.led, e);
+                        GpioLogicOut::None
                     }
-                }
+                };
+            }
+            if let Some(event_code) = data_obj.event_code() {
+                let event_code = match event_code {
+                    Ok(event_code) => event_code,
+                    Err(e) => {
+                        log::warn!("led: {}", e);
+                        return GpioLogicOut::None;
+                    }
+                };
+                // Ở đây bạn có thể làm bất cứ điều gì với event_code, ví dụ:
+                log::info!("Event Code: {}", event_code);
+                if device == DeviceOs::Hc {   
+                    let mut pin = event_code / 10;
+                    let state = event_code % 10;
+            
+                    let mut event = GpioLogicOut::StopEvent;
+                    log::info!("Pin: {}, state:{}", pin, state);
+                    if pin < 2 {
+                        return event;
+                    }
+                    pin -= 2;
+                    
+                    match state {
+                        ON!() => event =  GpioLogicOut::LedOnEvent { led_pin: pin as u64 }, 
+                        OFF!() => event = GpioLogicOut::LedOffEvent {led_pin:pin as u64}, 
+                        BLINK!()=> event=GpioLogicOut::LedBlinkEvent {led_pin:pin as u64, blink:false, time:20, fre: 1},
+                        _ => {}
+                    }
+                    return event;
+                }   
+                // device ai
+                else {
+                    return self.parse_led_ai(event_code);
+                }   
             }
         }
         GpioLogicOut::None
@@ -276,10 +311,9 @@ impl OtaLogic {
                     Ok(transport ) => {
                         match transport {
                             TransportOut::ResponseMqttEvent(parsed_json) => {
-                                let cmd = parsed_json["cmd"].as_str().unwrap_or("default_cmd");
-                                match cmd {
+                                match parsed_json.cmd.as_str() {
                                     SET!() => {
-                                        if let Some(_) = parsed_json.get("control_source") {
+                                        if parsed_json.control_source.is_some() {
                                             // Relay...
                                             let res = self.relay_handle(parsed_json);
                                             self.outputs.push_back(res);
@@ -304,15 +338,30 @@ impl OtaLogic {
                                 }
 
                             }
-                        }
-                    }
-                    Err(e) => {
-                        match e {
-                            _ => {
-                                
+                            TransportOut::Connected => {
+                                if let Some(led_pin) = self.network_led {
+                                    self.outputs.push_back(GpioLogicOut::LedOnEvent{led_pin});
+                                }
+                            }
+                            TransportOut::Disconnected => {
+                                if let Some(led_pin) = self.network_led {
+                                    self.outputs.push_back(GpioLogicOut::LedBlinkEvent{led_pin, blink: true, time: 0, fre: 500});
+                                }
+                            }
+                            TransportOut::SetRelay{relay, on} => {
+                                // no MQTT request to answer, the status still goes out
+                                let json_init = Request {
+                                    control_source: Some(ControlSource { kind: "dbus".to_string(), ..Default::default() }),
+                                    ..Default::default()
+                                };
+                                self.outputs.push_back(GpioLogicOut::RelayEvent{relays: vec![(Ok(relay), RelayCommand::set(on))], json_init});
+                            }
+                            TransportOut::SetLed{led, mode} => {
+                                self.outputs.push_back(led_event(led, mode));
                             }
                         }
                     }
+                    Err(_e) => {}
                 }  
             }
             GpioLogicIn::Gpio(result) => {
@@ -320,42 +369,39 @@ impl OtaLogic {
                     Ok(gpio)=>{
                         match gpio {
                             GpioOut::LedBlinkContinue {led_pin, blink, time, fre} => {
-                                self.outputs.push_back(GpioLogicOut::LedBlinkContinueEvent {led_pin,  blink, time: time, fre: fre });
-                            }
-                            GpioOut::Stop => {
-                              self.outputs.push_back(GpioLogicOut::StopEvent);  
+                                self.outputs.push_back(GpioLogicOut::LedBlinkContinueEvent {led_pin, blink, time, fre});
                             }
                             GpioOut::ButtonPressed => {
-                                self.outputs.push_back(GpioLogicOut::ButtonBlinkEvent);
+                                self.hold_stage = 0;
+                                self.outputs.push_back(GpioLogicOut::HoldProgressEvent{stage: 0});
                                 log::info!("Logic button pressed");
                             }
                             GpioOut::ButtonReleased => {
                                 log::info!("Logic button released");
                                 self.outputs.push_back(GpioLogicOut::ReturnState);
                             }
-                            GpioOut::LedTwoReleased => {
-                                log::info!("Led two released");
-                            }
-                            GpioOut::LedThreeReleased => {
-                                log::info!("Led three released");
-                            }
-                            GpioOut::LedFourReleased => {
-                                log::info!("Led four released");
+                            GpioOut::Gesture(Gesture::HoldStage{n}) => {
+                                self.hold_stage = n;
+                                self.outputs.push_back(GpioLogicOut::HoldProgressEvent{stage: n});
                             }
-                            GpioOut::LedFiveReleased => {
-                                log::info!("Led five released");
+                            GpioOut::Gesture(gesture) => {
+                                log::info!("Gesture {:?} at hold stage {}", gesture, self.hold_stage);
+                                if let Some(action) = self.gestures.action(&gesture, self.hold_stage) {
+                                    self.outputs.push_back(GpioLogicOut::ButtonActionEvent{action});
+                                }
                             }
                         }
                     }
 
-                    Err(e) =>{
-                        match e {
-                            _ =>  {
-                                
-                            }
-                        }
-                    }
+                    Err(_e) => {}
+                }
+            }
+            GpioLogicIn::Action(ActionOut::Done{action, result}) => {
+                match &result {
+                    Ok(()) => log::info!("Action {} done", action),
+                    Err(e) => log::error!("Action {} failed: {:?}", action, e),
                 }
+                self.outputs.push_back(GpioLogicOut::ActionDoneEvent{action, result});
             }
         }
     }
@@ -367,7 +413,38 @@ impl OtaLogic {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    #[test]
+    fn test_relay_batch() {
+        let mut logic = OtaLogic::new(DeviceOs::Ai, "AB12".to_string(), GestureMap::default(), None);
+        logic.outputs.clear();
+        let set = br#"{"cmd":"set","control_source":{"type":"app"},"objects":[
+            {"data":["io-AB12-0","io-CD34-1","io-AB12-2"],"execution":{"command":"OnOff","params":{"on":true}}},
+            {"data":["io-AB12-1"],"execution":{"command":"OnOff","params":{"on":false}}}
+        ],"reqid":"r1","source":"app"}"#;
+        logic.on_event(GpioLogicIn::Transport(Ok(TransportOut::ResponseMqttEvent(Request::parse(set).unwrap()))));
+        match logic.outputs.pop_front() {
+            Some(GpioLogicOut::RelayEvent{relays, json_init}) => {
+                assert_eq!(relays, vec![(Ok(0), RelayCommand::set(true)), (Ok(2), RelayCommand::set(true)), (Ok(1), RelayCommand::set(false))]);
+                assert_eq!(json_init.reqid, "r1");
+            }
+            other => panic!("expected a relay event, got {:?}", other),
+        }
 
-    // use super::*;
-   
+        // nothing for this device
+        let other = br#"{"cmd":"set","control_source":{},"objects":[{"data":["io-CD34-1"],"execution":{"params":{"on":true}}}]}"#;
+        logic.on_event(GpioLogicIn::Transport(Ok(TransportOut::ResponseMqttEvent(Request::parse(other).unwrap()))));
+        assert!(matches!(logic.outputs.pop_front(), Some(GpioLogicOut::None)));
+
+        // a hash without a relay index is answered with an error, not relay 0
+        let bad = br#"{"cmd":"set","control_source":{},"objects":[{"data":["io-AB12-x","io-AB12-1"],"execution":{"params":{"on":true}}}]}"#;
+        logic.on_event(GpioLogicIn::Transport(Ok(TransportOut::ResponseMqttEvent(Request::parse(bad).unwrap()))));
+        match logic.outputs.pop_front() {
+            Some(GpioLogicOut::RelayEvent{relays, ..}) => {
+                assert_eq!(relays, vec![(Err("io-AB12-x".to_string()), RelayCommand::set(true)), (Ok(1), RelayCommand::set(true))]);
+            }
+            other => panic!("expected a relay event, got {:?}", other),
+        }
+    }
 }
@@ -1,54 +1,255 @@
 use clap::Parser;
-use system_intergration::SystemIntergration;
-pub mod system_intergration;
-pub mod logic;
-pub mod transport;
-pub mod error;
-pub mod gpio;
-pub mod json;
+use io_service::system_intergration::SystemIntergration;
+use io_service::config::Config;
+use std::path::PathBuf;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
 
 /*
+RUST_LOG=info ./io-service --config=/etc/io-service/io-service.toml
+
+every setting of the file can be overridden by an arg or an IO_SERVICE_* env var:
 RUST_LOG=info ./io-service \
 --button=14 \
 --time-blink=1000 \
 --leds=10,11,12,13
+
+pins are sysfs numbers on the sysfs backend; chardev needs gpiochip offsets
+or line names:
+--gpio-backend=chardev --leds=gpiochip0:10,gpiochip0:11+active-low,LED_ZIGBEE
 */
 
+const DEFAULT_CONFIG: &str = "/etc/io-service/io-service.toml";
+
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short, long)]
-    device: String,
+    // TOML config file; the default path is optional, an explicit one is not
+    #[clap(short, long, env = "IO_SERVICE_CONFIG")]
+    config: Option<PathBuf>,
 
-    #[clap(short, long,default_value="0")]
-    leds: String,
+    // print the effective config (file + args + env) and exit
+    #[clap(long)]
+    print_config: bool,
 
-    #[clap(short, long,default_value="0")]
-    ios: String,
+    #[clap(short, long, env = "IO_SERVICE_DEVICE")]
+    device: Option<String>,
 
-    #[clap(short, long,default_value="0")]
-    fans: String,
+    // fixed device id instead of deriving it
+    #[clap(long, env = "IO_SERVICE_ID_MAC")]
+    id_mac: Option<String>,
 
-    #[clap(short, long,default_value="0")]
-    time_blink: u64,
+    // where to derive the device id from, in order: interface,machine-id,serial
+    #[clap(long, env = "IO_SERVICE_ID_SOURCES")]
+    id_sources: Option<String>,
 
-    #[clap(short, long,default_value="0")]
-    button: u64,
-}
+    #[clap(long, env = "IO_SERVICE_ID_INTERFACE")]
+    id_interface: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_KEEPALIVE_S")]
+    keepalive_s: Option<u64>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT")]
+    mqtt: Option<bool>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_HOST")]
+    mqtt_host: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_PORT")]
+    mqtt_port: Option<u16>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_CLIENT_ID")]
+    mqtt_client_id: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_USERNAME")]
+    mqtt_username: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_PASSWORD", hide_env_values = true)]
+    mqtt_password: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_PASSWORD_FILE")]
+    mqtt_password_file: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_TLS")]
+    mqtt_tls: Option<bool>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_CA_FILE")]
+    mqtt_ca_file: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_CERT_FILE")]
+    mqtt_cert_file: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_KEY_FILE")]
+    mqtt_key_file: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_MQTT_SERVER_NAME")]
+    mqtt_server_name: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_NETWORK_LED")]
+    network_led: Option<u64>,
+
+    #[clap(long, env = "IO_SERVICE_GPIO_BACKEND")]
+    gpio_backend: Option<String>,
+
+    #[clap(short, long, env = "IO_SERVICE_LEDS")]
+    leds: Option<String>,
+
+    #[clap(short, long, env = "IO_SERVICE_IOS")]
+    ios: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_RELAY_POLARITY")]
+    relay_polarity: Option<String>,
+
+    // per relay last, off, on or cloud
+    #[clap(long, env = "IO_SERVICE_RELAY_POWER_ON")]
+    relay_power_on: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_RELAY_STATE_FILE")]
+    relay_state_file: Option<String>,
+
+    // e.g. "0+1:exclusive;2+3:dead-time=500"
+    #[clap(long, env = "IO_SERVICE_RELAY_INTERLOCK")]
+    relay_interlock: Option<String>,
+
+    #[clap(short, long, env = "IO_SERVICE_FANS")]
+    fans: Option<String>,
+
+    #[clap(short, long, env = "IO_SERVICE_TIME_BLINK")]
+    time_blink: Option<u64>,
+
+    #[clap(short, long, env = "IO_SERVICE_BUTTON")]
+    button: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_DEBOUNCE_MS")]
+    debounce_ms: Option<u64>,
+
+    #[clap(long, env = "IO_SERVICE_LONG_PRESS_MS")]
+    long_press_ms: Option<u64>,
+
+    #[clap(long, env = "IO_SERVICE_DOUBLE_CLICK_MS")]
+    double_click_ms: Option<u64>,
+
+    #[clap(long, env = "IO_SERVICE_GESTURES")]
+    gestures: Option<String>,
 
-async fn cup_comma(input:String) -> Vec<u64> {
+    #[clap(long, env = "IO_SERVICE_ACTIONS")]
+    actions: Option<String>,
 
-    let mut numbers: Vec<u64> = Vec::new();
+    #[clap(long, env = "IO_SERVICE_FAN_CURVE")]
+    fan_curve: Option<String>,
 
-    let parts: Vec<&str> = input.split(',').collect();
+    #[clap(long, env = "IO_SERVICE_FAN_HYSTERESIS")]
+    fan_hysteresis: Option<f32>,
 
-    // convert u64 Vec
-    for part in parts {
-        if let Ok(number) = part.parse::<u64>() {
-            numbers.push(number);
+    #[clap(long, env = "IO_SERVICE_FAN_DWELL_MS")]
+    fan_dwell_ms: Option<u64>,
+
+    #[clap(long, env = "IO_SERVICE_FAN_FAIL_LIMIT")]
+    fan_fail_limit: Option<u32>,
+
+    #[clap(long, env = "IO_SERVICE_THERMAL_ROOT")]
+    thermal_root: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_THERMAL_ZONES")]
+    thermal_zones: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_THERMAL_POLICY")]
+    thermal_policy: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_DBUS")]
+    dbus: Option<bool>,
+
+    // system or session
+    #[clap(long, env = "IO_SERVICE_DBUS_BUS")]
+    dbus_bus: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_DBUS_NAME")]
+    dbus_name: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_SOCKET")]
+    socket: Option<bool>,
+
+    #[clap(long, env = "IO_SERVICE_SOCKET_PATH")]
+    socket_path: Option<String>,
+
+    // octal, e.g. 0660
+    #[clap(long, env = "IO_SERVICE_SOCKET_MODE")]
+    socket_mode: Option<String>,
+
+    #[clap(long, env = "IO_SERVICE_HTTP")]
+    http: Option<bool>,
+
+    // e.g. 0.0.0.0:8080
+    #[clap(long, env = "IO_SERVICE_HTTP_LISTEN")]
+    http_listen: Option<String>,
+}
+
+macro_rules! OVERRIDE {
+    ($field:expr, $arg:expr) => {
+        {
+            if let Some(value) = $arg {
+                $field = value;
+            }
         }
-    }
-    numbers
+    };
+}
+
+fn load_config(args: Args) -> Result<Config, String> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None if std::path::Path::new(DEFAULT_CONFIG).exists() => Config::load(DEFAULT_CONFIG.as_ref())?,
+        None => Config::default(),
+    };
+
+    OVERRIDE!(config.device, args.device);
+    OVERRIDE!(config.identity.id_mac, args.id_mac);
+    OVERRIDE!(config.identity.sources, args.id_sources);
+    OVERRIDE!(config.identity.interface, args.id_interface);
+    OVERRIDE!(config.keepalive_s, args.keepalive_s);
+    OVERRIDE!(config.mqtt.enabled, args.mqtt);
+    OVERRIDE!(config.mqtt.host, args.mqtt_host);
+    OVERRIDE!(config.mqtt.port, args.mqtt_port);
+    OVERRIDE!(config.mqtt.client_id, args.mqtt_client_id);
+    OVERRIDE!(config.mqtt.username, args.mqtt_username);
+    OVERRIDE!(config.mqtt.password, args.mqtt_password);
+    OVERRIDE!(config.mqtt.password_file, args.mqtt_password_file);
+    OVERRIDE!(config.mqtt.tls.enabled, args.mqtt_tls);
+    OVERRIDE!(config.mqtt.tls.ca_file, args.mqtt_ca_file);
+    OVERRIDE!(config.mqtt.tls.cert_file, args.mqtt_cert_file);
+    OVERRIDE!(config.mqtt.tls.key_file, args.mqtt_key_file);
+    OVERRIDE!(config.mqtt.tls.server_name, args.mqtt_server_name);
+    OVERRIDE!(config.gpio.backend, args.gpio_backend);
+    OVERRIDE!(config.gpio.leds, args.leds);
+    OVERRIDE!(config.gpio.ios, args.ios);
+    OVERRIDE!(config.gpio.relay_polarity, args.relay_polarity);
+    OVERRIDE!(config.gpio.relay_power_on, args.relay_power_on);
+    OVERRIDE!(config.gpio.relay_state_file, args.relay_state_file);
+    OVERRIDE!(config.gpio.relay_interlock, args.relay_interlock);
+    OVERRIDE!(config.gpio.fans, args.fans);
+    OVERRIDE!(config.gpio.network_led, args.network_led.map(Some));
+    OVERRIDE!(config.gpio.time_blink_ms, args.time_blink);
+    OVERRIDE!(config.button.pin, args.button);
+    OVERRIDE!(config.button.debounce_ms, args.debounce_ms);
+    OVERRIDE!(config.button.long_press_ms, args.long_press_ms);
+    OVERRIDE!(config.button.double_click_ms, args.double_click_ms);
+    OVERRIDE!(config.button.gestures, args.gestures);
+    OVERRIDE!(config.button.actions, args.actions);
+    OVERRIDE!(config.fan.curve, args.fan_curve);
+    OVERRIDE!(config.fan.hysteresis, args.fan_hysteresis);
+    OVERRIDE!(config.fan.dwell_ms, args.fan_dwell_ms);
+    OVERRIDE!(config.fan.fail_limit, args.fan_fail_limit);
+    OVERRIDE!(config.thermal.root, args.thermal_root);
+    OVERRIDE!(config.thermal.zones, args.thermal_zones);
+    OVERRIDE!(config.thermal.policy, args.thermal_policy);
+    OVERRIDE!(config.dbus.enabled, args.dbus);
+    OVERRIDE!(config.dbus.bus, args.dbus_bus);
+    OVERRIDE!(config.dbus.name, args.dbus_name);
+    OVERRIDE!(config.socket.enabled, args.socket);
+    OVERRIDE!(config.socket.path, args.socket_path);
+    OVERRIDE!(config.socket.mode, args.socket_mode);
+    OVERRIDE!(config.http.enabled, args.http);
+    OVERRIDE!(config.http.listen, args.http_listen);
+    Ok(config)
 }
 
 #[tokio::main]
@@ -56,39 +257,59 @@ async fn main() {
     env_logger::builder().format_timestamp_millis().init();
 
     let args = Args::parse();
-    log::info!("args: {:?}", args);
-
-    let time_blink = args.time_blink;
-    let button = args.button;
-
-    let leds_string = args.leds;
-    let ios_string = args.ios;
-    let fans_string = args.fans;
-    let device = args.device;
+    let print_config = args.print_config;
 
+    let config = match load_config(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("config: {}", e);
+            std::process::exit(2);
+        }
+    };
+    // printed before validating, so a config that fails can be looked at
+    if print_config {
+        print!("{}", config.to_toml());
+    }
+    let settings = match config.validate() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("config: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if print_config {
+        return;
+    }
 
-    let leds = cup_comma(leds_string).await;
-    let ios = cup_comma(ios_string).await;
-    let fans= cup_comma(fans_string).await;
+    log::info!("vec leds: {:?}", settings.leds);
+    log::info!("vec ios: {:?}", settings.ios);
+    log::info!("vec fans: {:?}", settings.fans);
 
-    log::info!("vec leds: {:?}", leds);
-    log::info!("vec ios: {:?}",ios);
-    log::info!("vec fans: {:?}",fans);
-    let id_mac = "Mi8ea43769e4d6Qb".to_string();
-    // Use numbers as needed in your application logic
-    let mut system_intergration = SystemIntergration::new(device, id_mac , leds, ios, fans, time_blink, button).await;
+    let mut system_intergration = SystemIntergration::new(settings).await;
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            log::error!("SIGTERM handler: {}", e);
+            return;
+        }
+    };
     loop {
-        match system_intergration.recv().await {
-            Ok(_) => {
-                
-            },
-            Err(e) => {
-                log::error!("{:?}", e);
+        select! {
+            _ = tokio::signal::ctrl_c() => {
+                log::info!("SIGINT, shutting down");
+                break;
+            }
+            _ = terminate.recv() => {
+                log::info!("SIGTERM, shutting down");
                 break;
             }
+            result = system_intergration.recv() => {
+                if let Err(e) = result {
+                    log::error!("{:?}", e);
+                    break;
+                }
+            }
         }
-
     }
+    system_intergration.shutdown().await;
 }
-
-
@@ -0,0 +1,464 @@
+use std::fmt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::error::ProtocolErr;
+use crate::transport::LedMode;
+
+/*
+Every message on the io topics is an envelope:
+
+{
+    "cmd": "set",
+    "control_source": {"id": "", "previous_control_reqid": "", "type": "app"},
+    "objects": [{"bridge_key": "io", "data": [...], "execution": {...}, "type": "devices"}],
+    "reqid": "4YmPnprQ2DWR",
+    "source": "app"
+}
+
+`D` is what the objects carry in "data". The cloud leaves fields out freely,
+so everything but "cmd" has a default.
+*/
+
+pub const BRIDGE_KEY: &str = "io";
+// "source" of everything io-service sends
+pub const SOURCE: &str = "io";
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct ControlSource {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub previous_control_reqid: String,
+    // app, dbus, http, io-ctl...
+    #[serde(rename = "type", default)]
+    pub kind: String,
+    // whatever else the cloud puts here, echoed back untouched (boxed to
+    // keep Request small)
+    #[serde(flatten)]
+    pub extra: Box<serde_json::Map<String, Value>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Params {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on: Option<bool>,
+    // back to the other state after this long
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    // applied only after this long
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_ms: Option<u64>,
+    // on for this long, then off; "on" is not needed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pulse_ms: Option<u64>,
+    // drop the pending timer of the relay and change nothing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancel: Option<bool>,
+}
+
+// What a relay "set" asks of one relay. Any command replaces the timer
+// pending on that relay.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RelayCommand {
+    pub on: bool,
+    pub delay_ms: Option<u64>,
+    // after `on` is applied, switch back once this has passed
+    pub duration_ms: Option<u64>,
+    pub cancel: bool,
+}
+
+impl RelayCommand {
+    pub fn set(on: bool) -> Self {
+        RelayCommand { on, ..Default::default() }
+    }
+
+    pub fn from_params(params: &Params) -> Result<Self, ProtocolErr> {
+        if params.cancel == Some(true) {
+            return Ok(RelayCommand { cancel: true, ..Default::default() });
+        }
+        for (field, ms) in [("duration_ms", params.duration_ms), ("delay_ms", params.delay_ms), ("pulse_ms", params.pulse_ms)] {
+            if ms == Some(0) {
+                return Err(ProtocolErr::Invalid { field, value: "0".to_string() });
+            }
+        }
+        match params.pulse_ms {
+            Some(pulse) => {
+                if params.duration_ms.is_some() || params.on == Some(false) {
+                    return Err(ProtocolErr::Invalid { field: "pulse_ms", value: format!("{} with duration_ms or on false", pulse) });
+                }
+                Ok(RelayCommand { on: true, delay_ms: params.delay_ms, duration_ms: Some(pulse), cancel: false })
+            }
+            None => {
+                let on = params.on.ok_or(ProtocolErr::Missing("objects[].execution.params.on"))?;
+                Ok(RelayCommand { on, delay_ms: params.delay_ms, duration_ms: params.duration_ms, cancel: false })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Execution {
+    // device trait the params are for, "OnOff"
+    #[serde(default)]
+    pub command: String,
+    #[serde(default)]
+    pub params: Params,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Object<D> {
+    #[serde(default)]
+    pub bridge_key: String,
+    #[serde(default = "Vec::new")]
+    pub data: Vec<D>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub execution: Option<Execution>,
+    // devices, devices_local, led, temperature, keepalive...
+    #[serde(rename = "type", default)]
+    pub kind: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope<D> {
+    pub cmd: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control_source: Option<ControlSource>,
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub objects: Vec<Object<D>>,
+    #[serde(default)]
+    pub reqid: String,
+    #[serde(default)]
+    pub source: String,
+    // only in "error" replies to local requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<D> Default for Envelope<D> {
+    fn default() -> Self {
+        Envelope { cmd: String::new(), control_source: None, objects: Vec::new(), reqid: String::new(), source: String::new(), error: None }
+    }
+}
+
+impl<D> Envelope<D> {
+    // One object of `kind` holding `data`, sent by io-service.
+    pub fn new(cmd: &str, kind: &str, data: Vec<D>) -> Self {
+        Envelope {
+            cmd: cmd.to_string(),
+            objects: vec![Object { bridge_key: BRIDGE_KEY.to_string(), data, execution: None, kind: kind.to_string() }],
+            source: SOURCE.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // "type" of the first object, what the message is about
+    pub fn kind(&self) -> &str {
+        self.objects.first().map_or("", |object| object.kind.as_str())
+    }
+
+    pub fn first(&self) -> Option<&D> {
+        self.objects.first().and_then(|object| object.data.first())
+    }
+}
+
+impl<D: DeserializeOwned> Envelope<D> {
+    pub fn parse(payload: &[u8]) -> Result<Self, ProtocolErr> {
+        serde_json::from_slice(payload).map_err(|e| ProtocolErr::Malformed(e.to_string()))
+    }
+}
+
+// reqid of a payload that did not parse as an envelope, to echo it in the
+// error reply
+pub fn reqid_of(payload: &[u8]) -> String {
+    serde_json::from_slice::<Value>(payload)
+        .ok()
+        .and_then(|value| value.get("reqid").and_then(Value::as_str).map(String::from))
+        .unwrap_or_default()
+}
+
+impl<D: Serialize> fmt::Display for Envelope<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&serde_json::to_string(self).map_err(|_| fmt::Error)?)
+    }
+}
+
+// "data" of a set or get. Relay commands carry hashes, led commands either
+// an index or an event code; anything else is kept as it came.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RequestData {
+    // io-<id>-<n>
+    Hash(String),
+    Led(LedData),
+    EventCode { event_code: String },
+    Other(Value),
+}
+
+impl RequestData {
+    pub fn event_code(&self) -> Option<Result<u32, ProtocolErr>> {
+        match self {
+            RequestData::EventCode { event_code } => Some(
+                event_code.parse().map_err(|_| ProtocolErr::Invalid { field: "event_code", value: event_code.clone() }),
+            ),
+            _ => None,
+        }
+    }
+}
+
+// direct led form from local tools: {"led": 1, "mode": "blink", "fre": 500}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedData {
+    pub led: u64,
+    pub mode: String,
+    // blink period in ms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fre: Option<u16>,
+}
+
+pub type Request = Envelope<RequestData>;
+
+impl Request {
+    // hash of the relay a "set" is for
+    pub fn hash(&self) -> Option<&str> {
+        match self.first() {
+            Some(RequestData::Hash(hash)) => Some(hash),
+            _ => None,
+        }
+    }
+
+    // Every (hash, command) a relay "set" asks for, in order. Each object
+    // applies its own OnOff execution to all of its hashes; objects whose
+    // params do not make a command are left out.
+    pub fn changes(&self) -> Vec<(&str, RelayCommand)> {
+        let mut changes = Vec::new();
+        for object in &self.objects {
+            let params = object.execution.as_ref().map(|execution| execution.params.clone()).unwrap_or_default();
+            let Ok(command) = RelayCommand::from_params(&params) else {
+                continue;
+            };
+            for item in &object.data {
+                if let RequestData::Hash(hash) = item {
+                    changes.push((hash.as_str(), command));
+                }
+            }
+        }
+        changes
+    }
+
+    // Everything OtaLogic relies on for a "set" or "get", checked before the
+    // request is acted on. Every object of a relay "set" must name io-<id>-<n>
+    // hashes and say on or off; a led "set" only carries led indexes or
+    // numeric event codes.
+    pub fn validate(&self) -> Result<(), ProtocolErr> {
+        match self.cmd.as_str() {
+            "get" => Ok(()),
+            "set" if self.control_source.is_some() => {
+                for object in self.objects.iter().filter(|object| !object.data.is_empty()) {
+                    RelayCommand::from_params(&object.execution.as_ref().map(|execution| execution.params.clone()).unwrap_or_default())?;
+                    for item in &object.data {
+                        let hash = match item {
+                            RequestData::Hash(hash) => hash.clone(),
+                            other => serde_json::to_string(other).unwrap_or_default(),
+                        };
+                        let valid = match hash.split('-').collect::<Vec<_>>().as_slice() {
+                            ["io", id, n] => !id.is_empty() && n.parse::<usize>().is_ok(),
+                            _ => false,
+                        };
+                        if !valid {
+                            return Err(ProtocolErr::Invalid { field: "hash", value: hash });
+                        }
+                    }
+                }
+                match self.changes().is_empty() {
+                    true => Err(ProtocolErr::Missing("objects[0].data[0]")),
+                    false => Ok(()),
+                }
+            }
+            "set" => {
+                let mut data = self.objects.iter().flat_map(|object| &object.data).peekable();
+                if data.peek().is_none() {
+                    return Err(ProtocolErr::Missing("objects[0].data"));
+                }
+                for item in data {
+                    match item {
+                        RequestData::Led(led) => {
+                            led.mode.parse::<LedMode>().map_err(|_| ProtocolErr::Invalid { field: "mode", value: led.mode.clone() })?;
+                        }
+                        RequestData::EventCode { .. } => {
+                            item.event_code().transpose()?;
+                        }
+                        RequestData::Hash(hash) => return Err(ProtocolErr::Invalid { field: "data", value: hash.clone() }),
+                        RequestData::Other(value) => return Err(ProtocolErr::Invalid { field: "data", value: value.to_string() }),
+                    }
+                }
+                Ok(())
+            }
+            cmd => Err(ProtocolErr::UnknownCmd(cmd.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct OnOff {
+    pub on: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct States {
+    #[serde(rename = "OnOff")]
+    pub on_off: OnOff,
+}
+
+// the next change a relay timer will make
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimerState {
+    pub on: bool,
+    pub in_ms: u64,
+}
+
+// one relay in a status
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceState {
+    pub hash: String,
+    pub states: States,
+    // why the command was not applied (OtaErr::reason), the state is what
+    // the relay holds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timer: Option<TimerState>,
+}
+
+impl DeviceState {
+    pub fn new(hash: &str, on: bool) -> Self {
+        DeviceState { hash: hash.to_string(), states: States { on_off: OnOff { on } }, error: None, timer: None }
+    }
+}
+
+pub type Status = Envelope<DeviceState>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trait {
+    pub is_main: bool,
+    pub name: String,
+}
+
+// one relay in a sync
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    pub bridge_key: String,
+    pub hash: String,
+    #[serde(rename = "isDefault")]
+    pub is_default: bool,
+    pub mac: String,
+    pub macdev: String,
+    pub traits: Vec<Trait>,
+    // always SWITCH
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+pub type Sync = Envelope<DeviceConfig>;
+
+// keepalive and button commands carry no data
+pub type Empty = Envelope<Value>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Presence {
+    pub mac: String,
+    // online or offline
+    pub state: String,
+}
+
+// data of an error reply
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rejected {
+    // see ProtocolErr::reason
+    pub reason: String,
+    // where the rejected command came in
+    pub topic: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActionResult {
+    pub action: String,
+    pub ok: bool,
+    // OtaErr::reason when the action failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_request_parsing() {
+        let set = br#"{"cmd":"set","control_source":{"type":"app"},"objects":[{"data":["io-AB12-1"],"execution":{"command":"OnOff","params":{"on":true}}}],"reqid":"r1","source":"app"}"#;
+        let set = Request::parse(set).unwrap();
+        assert_eq!(set.hash(), Some("io-AB12-1"));
+        assert_eq!(set.changes(), vec![("io-AB12-1", RelayCommand::set(true))]);
+
+        let pulse = br#"{"cmd":"set","control_source":{},"objects":[{"data":["io-AB12-1"],"execution":{"params":{"pulse_ms":500,"delay_ms":100}}},{"data":["io-AB12-2"],"execution":{"params":{"cancel":true}}}]}"#;
+        let pulse = Request::parse(pulse).unwrap();
+        assert_eq!(pulse.validate(), Ok(()));
+        assert_eq!(pulse.changes(), vec![
+            ("io-AB12-1", RelayCommand { on: true, delay_ms: Some(100), duration_ms: Some(500), cancel: false }),
+            ("io-AB12-2", RelayCommand { cancel: true, ..Default::default() }),
+        ]);
+        assert_eq!(set.control_source.as_ref().map(|c| c.kind.as_str()), Some("app"));
+
+        let led = Request::parse(br#"{"cmd":"set","objects":[{"data":[{"event_code":"52"},{"led":1,"mode":"blink","fre":500}]}]}"#).unwrap();
+        assert_eq!(led.first().and_then(RequestData::event_code), Some(Ok(52)));
+        assert_eq!(led.objects[0].data[1], RequestData::Led(LedData { led: 1, mode: "blink".to_string(), fre: Some(500) }));
+        let bad = Request::parse(br#"{"cmd":"set","objects":[{"data":[{"event_code":"x"}]}]}"#).unwrap();
+        assert!(matches!(bad.first().and_then(RequestData::event_code), Some(Err(ProtocolErr::Invalid { .. }))));
+
+        assert!(matches!(Request::parse(b"not json"), Err(ProtocolErr::Malformed(_))));
+        assert!(matches!(Request::parse(br#"{"objects":[]}"#), Err(ProtocolErr::Malformed(_))));
+        assert!(matches!(Request::parse(b"[1,2]"), Err(ProtocolErr::Malformed(_))));
+    }
+
+    #[test]
+    fn test_validate() {
+        let reason = |payload: &str| Request::parse(payload.as_bytes()).and_then(|request| request.validate()).err().map(|e| e.reason());
+        assert_eq!(reason(r#"{"cmd":"set","control_source":{},"objects":[{"data":["io-AB12-1"],"execution":{"params":{"on":false}}}]}"#), None);
+        assert_eq!(reason(r#"{"cmd":"set","control_source":{},"objects":[{"data":["io-AB12-x"],"execution":{"params":{"on":false}}}]}"#), Some("invalid_field"));
+        assert_eq!(reason(r#"{"cmd":"set","control_source":{},"objects":[{"data":["io-AB12-1"]}]}"#), Some("missing_field"));
+        assert_eq!(reason(r#"{"cmd":"set","objects":[{"data":[{"led":0,"mode":"on"},{"event_code":"52"}]}]}"#), None);
+        assert_eq!(reason(r#"{"cmd":"set","objects":[{"data":[{"led":0,"mode":"strobe"}]}]}"#), Some("invalid_field"));
+        assert_eq!(reason(r#"{"cmd":"set","objects":[]}"#), Some("missing_field"));
+        assert_eq!(reason(r#"{"cmd":"set","control_source":{},"objects":[{"data":["io-AB12-1"],"execution":{"params":{"on":true}}},{"data":["io-AB12-2",{"x":1}],"execution":{"params":{"on":false}}}]}"#), Some("invalid_field"));
+        assert_eq!(reason(r#"{"cmd":"set","control_source":{},"objects":[{"data":["io-AB12-1"],"execution":{"params":{"on":true}}},{"data":["io-AB12-2"]}]}"#), Some("missing_field"));
+        assert_eq!(reason(r#"{"cmd":"set","control_source":{},"objects":[{"data":["io-AB12-1"],"execution":{"params":{"on":true,"duration_ms":0}}}]}"#), Some("invalid_field"));
+        assert_eq!(reason(r#"{"cmd":"set","control_source":{},"objects":[{"data":["io-AB12-1"],"execution":{"params":{"pulse_ms":500,"duration_ms":900}}}]}"#), Some("invalid_field"));
+        assert_eq!(reason(r#"{"cmd":"get"}"#), None);
+        assert_eq!(reason(r#"{"cmd":"reboot"}"#), Some("unknown_cmd"));
+        assert_eq!(reason(r#"{"cmd":"set","#), Some("malformed"));
+        assert_eq!(reqid_of(br#"{"cmd":7,"reqid":"r9"}"#), "r9");
+    }
+
+    #[test]
+    fn test_control_source_round_trip() {
+        let set = br#"{"cmd":"set","control_source":{"type":"app","id":"u1","session":"s7","hop":{"n":2}},"objects":[{"data":["io-AB12-1"],"execution":{"params":{"on":true}}}],"reqid":"r1"}"#;
+        let set = Request::parse(set).unwrap();
+        let status = crate::json::status_value(&set, &[(true, "io-AB12-1".to_string())]);
+        let value: Value = serde_json::from_str(&status.to_string()).unwrap();
+        assert_eq!(value["control_source"], json!({"type": "app", "id": "u1", "previous_control_reqid": "", "session": "s7", "hop": {"n": 2}}));
+    }
+
+    #[test]
+    fn test_status_serialization() {
+        let mut status = Status::new("status", "devices", vec![DeviceState::new("io-AB12-0", true)]);
+        status.reqid = "r1".to_string();
+        let value: Value = serde_json::from_str(&status.to_string()).unwrap();
+        assert_eq!(value, json!({
+            "cmd": "status",
+            "objects": [{"bridge_key": "io", "data": [{"hash": "io-AB12-0", "states": {"OnOff": {"on": true}}}], "type": "devices"}],
+            "reqid": "r1",
+            "source": "io"
+        }));
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+// What a relay does when the service starts.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PowerOn {
+    // the state saved before the restart, off when there is none
+    #[default]
+    Last,
+    Off,
+    On,
+    // left as the pin is until the cloud sets it
+    Cloud,
+}
+
+impl std::str::FromStr for PowerOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last" => Ok(PowerOn::Last),
+            "off" => Ok(PowerOn::Off),
+            "on" => Ok(PowerOn::On),
+            "cloud" => Ok(PowerOn::Cloud),
+            _ => Err(format!("invalid power-on policy {:?}, expected last, off, on or cloud", s)),
+        }
+    }
+}
+
+// State a relay is driven to at start, None to leave it alone.
+pub fn power_on_states(policies: &[PowerOn], saved: &[bool]) -> Vec<Option<bool>> {
+    policies
+        .iter()
+        .enumerate()
+        .map(|(relay, policy)| match policy {
+            PowerOn::Last => Some(saved.get(relay).copied().unwrap_or(false)),
+            PowerOn::Off => Some(false),
+            PowerOn::On => Some(true),
+            PowerOn::Cloud => None,
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    relays: Vec<bool>,
+}
+
+// Relay states kept across restarts and power loss, as {"relays": [..]}.
+pub struct RelayStore {
+    path: PathBuf,
+}
+
+impl RelayStore {
+    pub fn new(path: &Path) -> Self {
+        RelayStore { path: path.to_path_buf() }
+    }
+
+    // The saved states; none when the file does not exist yet.
+    pub fn load(&self) -> Result<Vec<bool>, String> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("{:?}: {}", self.path, e)),
+        };
+        serde_json::from_str::<Saved>(&text).map(|saved| saved.relays).map_err(|e| format!("{:?}: {}", self.path, e))
+    }
+
+    // Written to a temporary file, synced and renamed over the old one, so a
+    // power cut leaves either the old or the new states.
+    pub fn save(&self, relays: &[bool]) -> Result<(), String> {
+        let tmp = self.path.with_extension("tmp");
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut file = std::fs::File::create(&tmp)?;
+            file.write_all(serde_json::to_string(&Saved { relays: relays.to_vec() })?.as_bytes())?;
+            file.sync_all()?;
+            std::fs::rename(&tmp, &self.path)?;
+            // make the rename itself durable
+            if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::File::open(dir)?.sync_all()?;
+            }
+            Ok(())
+        };
+        write().map_err(|e| format!("{:?}: {}", self.path, e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_store_and_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RelayStore::new(&dir.path().join("state/relays.json"));
+        assert_eq!(store.load(), Ok(vec![]));
+        store.save(&[true, false, true]).unwrap();
+        assert_eq!(store.load(), Ok(vec![true, false, true]));
+        assert!(!dir.path().join("state/relays.tmp").exists());
+
+        let policies: Vec<PowerOn> = ["last", "off", "on", "cloud", "last"].iter().map(|p| p.parse().unwrap()).collect();
+        assert_eq!(power_on_states(&policies, &[true, true, false, true]), vec![Some(true), Some(false), Some(true), None, Some(false)]);
+        assert!("restore".parse::<PowerOn>().is_err());
+
+        std::fs::write(dir.path().join("state/relays.json"), "{").unwrap();
+        assert!(store.load().is_err());
+    }
+}
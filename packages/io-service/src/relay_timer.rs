@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+use tokio::time::{Duration, Instant};
+use crate::protocol::TimerState;
+
+// A relay change waiting for its time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pending {
+    pub due: Instant,
+    pub on: bool,
+    // once applied, switch back after this long (delay then duration)
+    pub then_ms: Option<u64>,
+}
+
+// At most one pending change per relay, checked on the 100 ms tick. The
+// timers live in the service, not in a transport, so a broker reconnect does
+// not lose them.
+#[derive(Debug, Default)]
+pub struct RelayTimers {
+    pending: BTreeMap<usize, Pending>,
+}
+
+impl RelayTimers {
+    pub fn schedule(&mut self, relay: usize, on: bool, after_ms: u64, then_ms: Option<u64>, now: Instant) {
+        self.pending.insert(relay, Pending { due: now + Duration::from_millis(after_ms), on, then_ms });
+    }
+
+    pub fn cancel(&mut self, relay: usize) -> Option<Pending> {
+        self.pending.remove(&relay)
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.pending.values().any(|pending| pending.due <= now)
+    }
+
+    // Removes and returns the earliest timer due at `now`. One at a time, so
+    // applying it can still cancel the others.
+    pub fn next_due(&mut self, now: Instant) -> Option<(usize, Pending)> {
+        let (relay, _) = self.pending.iter().filter(|(_, pending)| pending.due <= now).min_by_key(|(relay, pending)| (pending.due, **relay))?;
+        let relay = *relay;
+        self.pending.remove(&relay).map(|pending| (relay, pending))
+    }
+
+    pub fn state(&self, relay: usize, now: Instant) -> Option<TimerState> {
+        self.pending.get(&relay).map(|pending| TimerState { on: pending.on, in_ms: pending.due.saturating_duration_since(now).as_millis() as u64 })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_relay_timers() {
+        let mut timers = RelayTimers::default();
+        let start = Instant::now();
+        timers.schedule(2, false, 30_000, None, start);
+        timers.schedule(1, true, 100, Some(500), start);
+        assert_eq!(timers.state(2, start + Duration::from_secs(10)), Some(TimerState { on: false, in_ms: 20_000 }));
+        assert!(!timers.is_due(start + Duration::from_millis(99)));
+
+        let now = start + Duration::from_millis(100);
+        assert!(timers.is_due(now));
+        assert_eq!(timers.next_due(now), Some((1, Pending { due: now, on: true, then_ms: Some(500) })));
+        assert_eq!(timers.next_due(now), None);
+
+        // a new schedule replaces the old one
+        timers.schedule(2, true, 10, None, now);
+        assert_eq!(timers.state(2, now).map(|t| t.on), Some(true));
+        assert!(timers.cancel(2).is_some());
+        assert_eq!(timers.state(2, now), None);
+    }
+}
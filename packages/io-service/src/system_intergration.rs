@@ -1,11 +1,23 @@
-use tokio::{time::{interval,Interval, Duration}, select};
-use crate::{gpio::GpioIn, json::JsonDriver, logic::OtaLogic, transport::mqtt::MqttDriver};
+use tokio::{time::{interval,Interval, Duration, Instant}, select};
+use crate::{gpio::GpioIn, logic::OtaLogic, transport::mqtt::MqttDriver};
 use crate::logic::{GpioLogicOut,GpioLogicIn,DeviceOs};
 use crate::error::OtaErr;
 use crate::gpio::GpioDriver;
 use crate::gpio::ButtonDriver;
 use crate::gpio::StatusGpio;
-use crate::json::JsonIn;
+use crate::fan::FanController;
+use crate::thermal::ThermalSource;
+use crate::config::Settings;
+use crate::action::{ActionDriver, ActionKind, ActionMap, ActionOut};
+use lumi_utils::timer::{SystemTimer, Timer};
+use crate::transport::{RelayResult, TransportIn, Transports};
+use crate::protocol::{ControlSource, RelayCommand, Request};
+use crate::relay_timer::RelayTimers;
+use crate::interlock::{Interlocks, Verdict};
+use crate::transport::dbus::DbusDriver;
+use crate::transport::socket::SocketDriver;
+use crate::transport::http::HttpDriver;
+use crate::relay_state::{power_on_states, PowerOn, RelayStore};
 use tokio::time::sleep;
 
 macro_rules! WAIT_UNLOCK {
@@ -19,17 +31,6 @@ macro_rules! WAIT_UNLOCK {
         }
     };
 }
-enum TemperatureLevel {
-    //Cpu temperature level
-    
-    Cool = 0,
-    CloseWarm = 1,
-    Warm = 2,
-    CloseHot = 3,
-    Hot = 4,
-    None = 5,
-}
-
 macro_rules! LOCK {
     ($object:expr) => { 
         {
@@ -46,57 +47,296 @@ macro_rules! UNLOCK {
     };
 }
 
+macro_rules! LOG_ERR {
+    ($result:expr) => { 
+        {
+            if let Err(e) = $result {
+                log::error!("gpio: {:?}", e);
+            }
+        }
+    };
+}
+
 pub struct SystemIntergration {
     interval: Interval,
     pub logic: OtaLogic,
-    transport: MqttDriver,
+    transports: Transports,
     gpio: GpioDriver,
     button: ButtonDriver,
-    json: JsonDriver,
+    actions: ActionDriver,
+    action_map: ActionMap,
+    fan: FanController,
+    thermal: ThermalSource,
+    relay_store: Option<RelayStore>,
+    relay_timers: RelayTimers,
+    interlocks: Interlocks,
+    timer: SystemTimer,
+    // 100 ms ticks between keepalives
+    keepalive_ticks: usize,
     index: usize,
 }
 
 impl SystemIntergration {
-    pub async fn new(device:String, id_mac:String, led_vec:Vec<u64>, io_vec:Vec<u64>, fan_vec:Vec<u64>, time_blink:u64, button_pin:u64) -> Self {
-        let mut _device = DeviceOs::None;
-        if device  == "Ai".to_string(){
-            _device = DeviceOs::Ai;
+    pub async fn new(settings: Settings) -> Self {
+        let _device = if settings.device == "Ai" {
+            DeviceOs::Ai
         }
         else {
-            _device = DeviceOs::Hc;
+            DeviceOs::Hc
+        };
+
+        let mut button = ButtonDriver::new(settings.backend.clone(), settings.button, Duration::from_millis(settings.debounce_ms), settings.gestures);
+        LOG_ERR!(button.start());
+        let mut transports = Transports::default();
+        if settings.mqtt.enabled {
+            transports.push(Box::new(MqttDriver::new(&settings.mqtt, settings.mqtt_tls.clone(), &settings.id_mac).await));
+        }
+        // a transport that cannot start is logged and left out
+        if let Some(config) = &settings.dbus {
+            if let Ok(dbus) = DbusDriver::new(config.bus == "session", &config.name).await {
+                transports.push(Box::new(dbus));
+            }
         }
+        if let Some((path, mode)) = &settings.socket {
+            if let Ok(socket) = SocketDriver::new(path, *mode, &settings.id_mac) {
+                transports.push(Box::new(socket));
+            }
+        }
+        if let Some(listen) = settings.http {
+            if let Ok(http) = HttpDriver::new(listen, &settings.id_mac).await {
+                transports.push(Box::new(http));
+            }
+        }
+        log::info!("transports: {:?}", transports.names());
 
-        SystemIntergration {
+        let mut system = SystemIntergration {
             interval: interval(Duration::from_millis(100)),
-            logic: OtaLogic::new(_device, id_mac),
-            transport: MqttDriver::new(
-                "io_service".to_string(),
-                "localhost".to_string(),
-                1883,
-                5,  
-            ).await,
-            gpio: GpioDriver::new(led_vec, io_vec,fan_vec, time_blink, button_pin),
-            button: ButtonDriver::new(button_pin),
-            json: JsonDriver{},
+            logic: OtaLogic::new(_device, settings.id_mac, settings.gesture_map, settings.network_led),
+            transports,
+            gpio: GpioDriver::new(settings.backend, settings.leds, settings.ios, settings.fans),
+            button,
+            actions: ActionDriver::new(),
+            action_map: settings.action_map,
+            fan: FanController::new(settings.fan_curve),
+            thermal: settings.thermal,
+            relay_store: settings.relay_state_file.as_deref().map(RelayStore::new),
+            relay_timers: RelayTimers::default(),
+            interlocks: settings.relay_interlock,
+            timer: SystemTimer::default(),
+            keepalive_ticks: (settings.keepalive_s * 10) as usize,
             index: 0,
+        };
+        // before anything is published, the first sync already has them
+        system.restore_relays(&settings.relay_power_on).await;
+        let relays = system.gpio.get_value_relay().await;
+        let _ = system.send(TransportIn::Relays(relays)).await;
+        let _ = system.send(TransportIn::Leds(system.gpio.get_value_led())).await;
+        system
+    }
+
+    // Fan out to every transport; failures are logged there and must not
+    // take the service down.
+    async fn send(&mut self, data: TransportIn) -> Result<(), OtaErr> {
+        self.transports.send(data).await
+    }
+
+    // Drive every relay as its power-on policy says. The cloud relays are read
+    // first, so the interlocks are checked against how every relay will end
+    // up; a driven relay wins over a cloud one, then the lower index.
+    async fn restore_relays(&mut self, policies: &[PowerOn]) {
+        let saved = match &self.relay_store {
+            Some(store) => store.load().unwrap_or_else(|e| {
+                log::error!("relay state: {}, starting from off", e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        let planned = power_on_states(policies, &saved);
+        let mut states = Vec::new();
+        for (relay, state) in planned.iter().enumerate() {
+            let on = match state {
+                Some(on) => *on,
+                None => match self.gpio.read_relay(relay) {
+                    Ok(on) => {
+                        log::info!("relay {}: power-on left {}", relay, if on { "on" } else { "off" });
+                        on
+                    }
+                    Err(e) => {
+                        log::error!("relay {}: {:?}", relay, e);
+                        false
+                    }
+                },
+            };
+            states.push(on);
+        }
+        let driven = (0..planned.len()).filter(|relay| planned[*relay].is_some());
+        let order: Vec<usize> = driven.chain((0..planned.len()).filter(|relay| planned[*relay].is_none())).collect();
+        let forced = self.interlocks.settle(&mut states, &order, Instant::now());
+        for relay in &forced {
+            log::warn!("relay {}: interlocked, stays off", relay);
         }
+
+        // everything going off first, so a group is never on together
+        for on in [false, true] {
+            for (relay, state) in planned.iter().enumerate() {
+                if states[relay] != on || (state.is_none() && !forced.contains(&relay)) {
+                    continue;
+                }
+                log::info!("relay {}: power-on {}", relay, if on { "on" } else { "off" });
+                let _ = self.drive_relay(relay, on).await;
+            }
+        }
+        self.save_relays().await;
+    }
+
+    async fn save_relays(&mut self) {
+        let relays = self.gpio.get_value_relay().await;
+        if let Some(store) = &self.relay_store {
+            if let Err(e) = store.save(&relays) {
+                log::error!("relay state: {}", e);
+            }
+        }
+    }
+
+    async fn drive_relay(&mut self, relay: usize, on: bool) -> Result<(), OtaErr> {
+        let result = match on {
+            true => self.gpio.send(GpioIn::RelayOn{pin:relay as u64}).await,
+            false => self.gpio.send(GpioIn::RelayOff{pin:relay as u64}).await,
+        };
+        match &result {
+            Ok(()) => self.interlocks.switched(relay, on, Instant::now()),
+            Err(e) => log::error!("relay {}: {:?}", relay, e),
+        }
+        result
+    }
+
+    // Drive the relay the way its interlock group allows, then schedule the
+    // switch back after `then_ms`. Relays the group switches off first are
+    // added to `results`.
+    async fn switch_relay(&mut self, relay: usize, on: bool, then_ms: Option<u64>, now: Instant, results: &mut Vec<RelayResult>) -> Result<(), OtaErr> {
+        let states = self.gpio.get_value_relay().await;
+        let (off, wait_ms) = match self.interlocks.check(relay, on, &states, now) {
+            Verdict::Allow { off, wait_ms } => (off, wait_ms),
+            Verdict::Forbid(other) => {
+                log::warn!("relay {}: not switched on, interlocked with relay {} which is on", relay, other);
+                return Err(OtaErr::InterlockErr);
+            }
+        };
+        for other in off {
+            log::warn!("relay {}: switched off, interlocked with relay {}", other, relay);
+            if let Some(pending) = self.relay_timers.cancel(other) {
+                log::warn!("relay {}: timer {:?} overridden by relay {}", other, pending, relay);
+            }
+            let result = self.drive_relay(other, false).await;
+            let failed = result.is_err();
+            results.push(self.relay_result(other, result, now).await);
+            // never on together, even when the other one is stuck
+            if failed {
+                return Err(OtaErr::InterlockErr);
+            }
+        }
+        if let Some(wait) = wait_ms {
+            log::info!("relay {}: dead time, on in {} ms", relay, wait);
+            self.relay_timers.schedule(relay, on, wait, then_ms, now);
+            return Ok(());
+        }
+        let result = self.drive_relay(relay, on).await;
+        if let (Ok(()), Some(then)) = (&result, then_ms) {
+            self.relay_timers.schedule(relay, !on, then, None, now);
+        }
+        result
+    }
+
+    // The state the relay holds (the new one on success, the old one with
+    // the error otherwise) and its pending timer.
+    async fn relay_result(&mut self, relay: usize, result: Result<(), OtaErr>, now: Instant) -> RelayResult {
+        let on = self.gpio.get_value_relay().await.get(relay).copied().unwrap_or(false);
+        RelayResult { relay, on, result, timer: self.relay_timers.state(relay, now), hash: None }
+    }
+
+    // Carry out the commands in order and report them in one status. Delayed
+    // changes and the switch back after a duration or pulse go to the relay
+    // timers; interlocks are checked when a relay is actually switched.
+    async fn set_relays(&mut self, relays: Vec<(Result<usize, String>, RelayCommand)>, json_init: Request) {
+        let now = Instant::now();
+        let before = self.gpio.get_value_relay().await;
+        let count = before.len();
+        let mut results = Vec::new();
+        for (relay, command) in relays {
+            let relay = match relay {
+                Ok(relay) if relay < count => relay,
+                Ok(relay) => {
+                    results.push(RelayResult { relay, on: false, result: Err(OtaErr::SelectPinErr), timer: None, hash: None });
+                    continue;
+                }
+                Err(hash) => {
+                    results.push(RelayResult { relay: 0, on: false, result: Err(OtaErr::SelectPinErr), timer: None, hash: Some(hash) });
+                    continue;
+                }
+            };
+            // whatever was pending is replaced
+            if let Some(pending) = self.relay_timers.cancel(relay) {
+                log::info!("relay {}: timer {:?} cancelled", relay, pending);
+            }
+            let result = if command.cancel {
+                Ok(())
+            }
+            else if let Some(delay) = command.delay_ms {
+                self.relay_timers.schedule(relay, command.on, delay, command.duration_ms, now);
+                Ok(())
+            }
+            else {
+                self.switch_relay(relay, command.on, command.duration_ms, now, &mut results).await
+            };
+            results.push(self.relay_result(relay, result, now).await);
+        }
+        if self.gpio.get_value_relay().await != before {
+            self.save_relays().await;
+        }
+        let _ = self.send(TransportIn::RelayStatus{relays: results, json_init}).await;
+    }
+
+    // Apply the timers that are due, reported like a command from "timer".
+    async fn fire_relay_timers(&mut self) {
+        let now = Instant::now();
+        let mut results = Vec::new();
+        while let Some((relay, pending)) = self.relay_timers.next_due(now) {
+            log::info!("relay {}: timer switches it {}", relay, if pending.on { "on" } else { "off" });
+            let result = self.switch_relay(relay, pending.on, pending.then_ms, now, &mut results).await;
+            results.push(self.relay_result(relay, result, now).await);
+        }
+        if results.is_empty() {
+            return;
+        }
+        self.save_relays().await;
+        let json_init = Request {
+            control_source: Some(ControlSource { kind: "timer".to_string(), ..Default::default() }),
+            ..Default::default()
+        };
+        let _ = self.send(TransportIn::RelayStatus{relays: results, json_init}).await;
+    }
+
+    pub async fn shutdown(&mut self) {
+        self.transports.shutdown().await;
     }
 
     pub async fn recv(&mut self) -> Result<(),OtaErr> {
         select! {
             _ = self.interval.tick() => {
-                let _ = self.button.button_handle().await;
                 self.logic.tick += 1;
                 self.index +=1;
+                if self.relay_timers.is_due(Instant::now()) {
+                    self.logic.outputs.push_back(GpioLogicOut::RelayTimerEvent);
+                }
 
-                if self.index >= 400 { //(40s) 
+                if self.index >= self.keepalive_ticks {
                     self.index = 0;
                     self.logic.outputs.push_back(GpioLogicOut::KeepAliveEvent);
                     self.logic.outputs.push_back(GpioLogicOut::CheckTempCpuEvent);
                 }
             },
 
-            etransport  = self.transport.recv() =>{
+            etransport = self.transports.recv() => {
                 self.logic.on_event(GpioLogicIn::Transport(etransport));
             },
 
@@ -107,103 +347,102 @@ impl SystemIntergration {
             ebutton = self.button.recv() => {
                 self.logic.on_event(GpioLogicIn::Gpio(ebutton));
             }
+
+            eaction = self.actions.recv() => {
+                self.logic.on_event(GpioLogicIn::Action(eaction));
+            }
         
         }
           
         while let Some(out) = self.logic.pop_action() {
             match out {
                     GpioLogicOut::LedOnEvent{led_pin} => {
-                        WAIT_UNLOCK!(self, GpioLogicOut::LedOnEvent {led_pin:led_pin});
+                        WAIT_UNLOCK!(self, GpioLogicOut::LedOnEvent {led_pin});
                         log::info!("On light event");
-                        self.gpio.send(GpioIn::LedOn {pin: led_pin}).await.unwrap();
+                        LOG_ERR!(self.gpio.send(GpioIn::LedOn {pin: led_pin}).await);
+                        let _ = self.send(TransportIn::Leds(self.gpio.get_value_led())).await;
                     }
                     GpioLogicOut::LedOffEvent{led_pin}  => {
-                        WAIT_UNLOCK!(self, GpioLogicOut::LedOffEvent {led_pin:led_pin});
+                        WAIT_UNLOCK!(self, GpioLogicOut::LedOffEvent {led_pin});
                         log::info!("On off event");
-                        self.gpio.send(GpioIn::LedOff {pin: led_pin}).await.unwrap();
+                        LOG_ERR!(self.gpio.send(GpioIn::LedOff {pin: led_pin}).await);
+                        let _ = self.send(TransportIn::Leds(self.gpio.get_value_led())).await;
                     }
 
                     GpioLogicOut::LedBlinkContinueEvent {led_pin, blink, time, fre }=> {
-                        WAIT_UNLOCK!(self, GpioLogicOut::LedBlinkContinueEvent  {led_pin: led_pin, blink:blink ,time: time, fre: fre });
-                        self.gpio.send(GpioIn::LedBlink {pin: led_pin ,blink:blink , time: time , fre: fre , get_tick: self.logic.tick.clone() }).await.unwrap();
+                        WAIT_UNLOCK!(self, GpioLogicOut::LedBlinkContinueEvent {led_pin, blink, time, fre});
+                        LOG_ERR!(self.gpio.send(GpioIn::LedBlink {pin: led_pin, blink, time, fre, get_tick: self.logic.tick}).await);
                     }
 
                     GpioLogicOut::LedBlinkEvent{led_pin, blink, time, fre } => {
-                        WAIT_UNLOCK!(self, GpioLogicOut::LedBlinkEvent {led_pin: led_pin, blink:blink ,time: time, fre: fre});
+                        WAIT_UNLOCK!(self, GpioLogicOut::LedBlinkEvent {led_pin, blink, time, fre});
                         self.gpio.status = StatusGpio::Blink;
                         log::info!("On blink event");
                         log::info!("Fre new : {:?}", fre);
-                        let check = self.gpio.blink_parse(led_pin, fre).await;
-                        match check {
-                            Ok(()) => self.gpio.send(GpioIn::LedBlink {pin: led_pin, blink:blink ,time: time, fre: fre , get_tick: self.logic.tick.clone() }).await.unwrap(),
-                            _ => {}
+                        if self.gpio.blink_parse(led_pin, fre).await.is_ok() {
+                            LOG_ERR!(self.gpio.send(GpioIn::LedBlink {pin: led_pin, blink, time, fre, get_tick: self.logic.tick}).await);
+                            let _ = self.send(TransportIn::Leds(self.gpio.get_value_led())).await;
                         }
                     }
-                    GpioLogicOut::ButtonBlinkEvent => {
+                    GpioLogicOut::HoldProgressEvent{stage} => {
                         LOCK!(self);
-                        self.gpio.send(GpioIn::ButonBlink).await.unwrap();
+                        LOG_ERR!(self.gpio.send(GpioIn::HoldProgress{stage}).await);
                     }
                     GpioLogicOut::ReturnState => {
                         UNLOCK!(self);
                         log::info!("Returning state");
-                        self.gpio.send(GpioIn::ReturnState).await.unwrap();
+                        LOG_ERR!(self.gpio.send(GpioIn::ReturnState).await);
                     }
 
-                    GpioLogicOut::RelayOnEvent{relay,json_init} => {
-                        self.gpio.send(GpioIn::RelayOn{pin:relay as u64}).await.unwrap();
-
-                        let topic = "component/io/status".to_string();
-                        let pin: Vec<(bool, String)> = vec![
-                            (true, format!("io-{}-{}", self.logic.id_mac.clone(),relay))
-                        ];
-                        let (mess, _) = self.json.convert(JsonIn::StatusConvert {json_init,pin}).await;
-
-                        self.transport.send(topic, mess.into(), rumqttc::QoS::AtMostOnce, false).await.unwrap();
+                    GpioLogicOut::ButtonActionEvent{action} => {
+                        log::info!("Button action: {}", action);
+                        let _ = self.send(TransportIn::ButtonEvent{action: action.clone()}).await;
+                        match self.action_map.kind(&action) {
+                            ActionKind::Mqtt{topic} => {
+                                let result = self.send(TransportIn::Command{action: action.clone(), topic}).await;
+                                self.logic.on_event(GpioLogicIn::Action(ActionOut::Done{action, result}));
+                            }
+                            kind => self.actions.send(action, kind),
+                        }
                     }
 
-                    GpioLogicOut::RelayOffEvent{relay, json_init} => {
-                        self.gpio.send(GpioIn::RelayOff{pin:relay as u64}).await.unwrap();
+                    GpioLogicOut::ActionDoneEvent{action, result} => {
+                        LOG_ERR!(self.gpio.send(GpioIn::Confirm{ok: result.is_ok()}).await);
+                        let _ = self.send(TransportIn::ActionDone{action, result}).await;
+                    }
 
-                        let topic = "component/io/status".to_string();
-                        let pin: Vec<(bool, String)> = vec![
-                            (false, format!("io-{}-{}", self.logic.id_mac.clone(),relay))
-                        ];
-                        let (mess, _) = self.json.convert(JsonIn::StatusConvert {json_init,pin}).await;
+                    GpioLogicOut::RelayEvent{relays, json_init} => {
+                        self.set_relays(relays, json_init).await;
+                    }
 
-                        self.transport.send(topic, mess.into(), rumqttc::QoS::AtMostOnce, false).await.unwrap();
+                    GpioLogicOut::RelayTimerEvent => {
+                        self.fire_relay_timers().await;
                     }
 
                     GpioLogicOut::ConfigRelayEvent=> {
-                        let status = self.gpio.get_value_relay().await;
-                        let (mess_sync , mess_st ) = self.json.convert( JsonIn::SyncConvert{status, mac_id:self.logic.id_mac.clone()}).await;
-
-                        // config
-                        let topic_sync = "component/io/config".to_string();
-                        self.transport.send(topic_sync, mess_sync.into(), rumqttc::QoS::AtMostOnce, false).await.unwrap();
-
-                        // status
-                        let topic_st = "component/io/status".to_string();
-                        self.transport.send(topic_st, mess_st.into(), rumqttc::QoS::AtMostOnce, false).await.unwrap();
+                        let relays = self.gpio.get_value_relay().await;
+                        let now = Instant::now();
+                        let timers = (0..relays.len()).map(|relay| self.relay_timers.state(relay, now)).collect();
+                        let _ = self.send(TransportIn::Sync { relays, timers }).await;
                     }
 
                     GpioLogicOut::KeepAliveEvent =>{
                         log::info!("Keep alive event");
-                        let topic = "component/keepalive/io-manager".to_string();                        
-
-                        let (mess, _) = self.json.convert(JsonIn::KeepAlive).await;
-                        self.transport.send(topic, mess.into(), rumqttc::QoS::AtMostOnce, false).await.unwrap();
+                        let _ = self.send(TransportIn::KeepAlive).await;
                     }
 
                     GpioLogicOut::CheckTempCpuEvent => {
                         log::info!("Check temperature !!!");
-                        match self.gpio.check_temp().await{
-                            Ok(cpu_temperature) => {
-                                self.gpio.control_fan(cpu_temperature as i32).await;
-                            }
-
-                            Err(_) => {
-
-                            }
+                        let reading = self.thermal.read().await;
+                        if let Ok(temp) = reading {
+                            log::info!("cpu temperature {:.1}", temp);
+                            let _ = self.send(TransportIn::Temperature(temp)).await;
+                        }
+                        let now = self.timer.now_ms();
+                        if let Some(level) = self.fan.update(reading, now) {
+                            let pattern = self.fan.pattern(level);
+                            LOG_ERR!(self.gpio.send(GpioIn::FanMode{level, pattern: pattern.clone()}).await);
+                            let _ = self.send(TransportIn::Fan{level, pattern}).await;
                         }
                     }
 
@@ -215,4 +454,73 @@ impl SystemIntergration {
         Ok(())
         }
         
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use crate::config::Config;
+    use crate::gpio::{Direction, GpioBackend};
+    use crate::gpio::line::PinSpec;
+    use crate::gpio::memory::MemoryBackend;
+    use crate::relay_state::RelayStore;
+
+    // two relays on the memory backend, no transport
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.identity.id_mac = "Mi8ea43769e4d6Qb".to_string();
+        config.mqtt.enabled = false;
+        config.socket.enabled = true;
+        config.gpio.backend = "memory".to_string();
+        config.gpio.leds = String::new();
+        config.gpio.ios = "20,21".to_string();
+        config.gpio.relay_state_file = String::new();
+        config
+    }
+
+    #[tokio::test]
+    async fn test_power_on_last_and_cloud_interlocked() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("relays.json");
+        RelayStore::new(&state_file).save(&[true, false]).unwrap();
+
+        let mut config = config();
+        config.gpio.relay_power_on = "last,cloud".to_string();
+        config.gpio.relay_interlock = "0+1:exclusive".to_string();
+        config.gpio.relay_state_file = state_file.to_string_lossy().to_string();
+        let mut settings = config.validate().unwrap();
+        settings.socket = None;
+
+        // the cloud relay was left on (active low) by the previous run
+        let backend = Arc::new(MemoryBackend::new());
+        backend.export(&PinSpec::number(21)).unwrap();
+        backend.set_direction(&PinSpec::number(21), Direction::Low).unwrap();
+        settings.backend = backend.clone();
+
+        let mut system = SystemIntergration::new(settings).await;
+        assert_eq!(system.gpio.get_value_relay().await, vec![true, false]);
+        assert_eq!(backend.value(&PinSpec::number(20)), Some(0));
+        assert_eq!(backend.value(&PinSpec::number(21)), Some(1));
+        assert_eq!(RelayStore::new(&state_file).load(), Ok(vec![true, false]));
+    }
+
+    #[tokio::test]
+    async fn test_due_timers_cancelled_by_an_earlier_one() {
+        let mut config = config();
+        config.gpio.relay_power_on = "off,on".to_string();
+        config.gpio.relay_interlock = "0+1:exclusive".to_string();
+        let mut settings = config.validate().unwrap();
+        settings.socket = None;
+        let mut system = SystemIntergration::new(settings).await;
+        assert_eq!(system.gpio.get_value_relay().await, vec![false, true]);
+
+        // both due together: relay 0 goes on first and turns 1 off, which
+        // drops the timer of 1 instead of letting it win the group back
+        let now = Instant::now();
+        system.relay_timers.schedule(0, true, 0, None, now);
+        system.relay_timers.schedule(1, true, 0, None, now);
+        system.fire_relay_timers().await;
+        assert_eq!(system.gpio.get_value_relay().await, vec![true, false]);
+        assert!(!system.relay_timers.is_due(Instant::now()));
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::fs;
+use crate::error::OtaErr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    Max,
+    Avg,
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(Aggregation::Max),
+            "avg" => Ok(Aggregation::Avg),
+            _ => Err(format!("invalid thermal policy {:?}, expected max or avg", s)),
+        }
+    }
+}
+
+// Reads CPU temperature from <root>/thermal_zone*/temp. Zones are picked by
+// their `type` file, an empty list takes every zone.
+pub struct ThermalSource {
+    root: PathBuf,
+    zone_types: Vec<String>,
+    policy: Aggregation,
+}
+
+// The thermal sysfs ABI always reports millidegrees.
+fn parse_temp(content: &str) -> Result<f32, OtaErr> {
+    let raw = content.trim().parse::<i64>().map_err(|_| OtaErr::ConvertTempErr)?;
+    Ok(raw as f32 / 1000.0)
+}
+
+impl ThermalSource {
+    pub fn new(root: impl AsRef<Path>, zone_types: Vec<String>, policy: Aggregation) -> Self {
+        ThermalSource {
+            root: root.as_ref().to_path_buf(),
+            zone_types,
+            policy,
+        }
+    }
+
+    pub async fn zones(&self) -> Result<Vec<PathBuf>, OtaErr> {
+        let mut dir = fs::read_dir(&self.root).await.map_err(|_| OtaErr::OpenFileErr)?;
+        let mut zones = Vec::new();
+
+        while let Some(entry) = dir.next_entry().await.map_err(|_| OtaErr::ReadFileErr)? {
+            if !entry.file_name().to_string_lossy().starts_with("thermal_zone") {
+                continue;
+            }
+            let path = entry.path();
+            if !self.zone_types.is_empty() {
+                let zone_type = fs::read_to_string(path.join("type")).await.unwrap_or_default();
+                if !self.zone_types.iter().any(|t| t == zone_type.trim()) {
+                    continue;
+                }
+            }
+            zones.push(path);
+        }
+        zones.sort();
+        Ok(zones)
+    }
+
+    // Temperature in °C over the selected zones.
+    pub async fn read(&self) -> Result<f32, OtaErr> {
+        let zones = self.zones().await?;
+        if zones.is_empty() {
+            log::warn!("no thermal zone matching {:?} in {:?}", self.zone_types, self.root);
+            return Err(OtaErr::OpenFileErr);
+        }
+
+        let mut temps = Vec::new();
+        for zone in zones {
+            let content = fs::read_to_string(zone.join("temp")).await.map_err(|_| OtaErr::ReadFileErr)?;
+            temps.push(parse_temp(&content)?);
+        }
+
+        let temp = match self.policy {
+            Aggregation::Max => temps.iter().cloned().fold(f32::MIN, f32::max),
+            Aggregation::Avg => temps.iter().sum::<f32>() / temps.len() as f32,
+        };
+        Ok(temp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn zone(root: &Path, index: usize, zone_type: &str, temp: &str) {
+        let dir = root.join(format!("thermal_zone{}", index));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("type"), format!("{}\n", zone_type)).unwrap();
+        std::fs::write(dir.join("temp"), format!("{}\n", temp)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_fake_sysfs() {
+        let root = tempfile::tempdir().unwrap();
+        zone(root.path(), 0, "cpu-thermal", "48500");
+        zone(root.path(), 1, "gpu-thermal", "52000");
+        zone(root.path(), 2, "soc", "500");
+
+        let cpu = ThermalSource::new(root.path(), vec!["cpu-thermal".to_string()], Aggregation::Max);
+        assert_eq!(cpu.read().await, Ok(48.5));
+
+        let both = vec!["cpu-thermal".to_string(), "gpu-thermal".to_string()];
+        let avg = ThermalSource::new(root.path(), both, Aggregation::Avg);
+        assert_eq!(avg.read().await, Ok(50.25));
+
+        let all = ThermalSource::new(root.path(), vec![], Aggregation::Max);
+        assert_eq!(all.read().await, Ok(52.0));
+
+        // below 1000 is still millidegrees
+        let soc = ThermalSource::new(root.path(), vec!["soc".to_string()], Aggregation::Max);
+        assert_eq!(soc.read().await, Ok(0.5));
+
+        let none = ThermalSource::new(root.path(), vec!["battery".to_string()], Aggregation::Max);
+        assert_eq!(none.read().await, Err(OtaErr::OpenFileErr));
+    }
+
+    #[tokio::test]
+    async fn test_read_bad_value() {
+        let root = tempfile::tempdir().unwrap();
+        zone(root.path(), 0, "cpu-thermal", "hot");
+
+        let cpu = ThermalSource::new(root.path(), vec![], Aggregation::Max);
+        assert_eq!(cpu.read().await, Err(OtaErr::ConvertTempErr));
+    }
+}
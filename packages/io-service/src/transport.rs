@@ -1,21 +1,214 @@
 pub mod mqtt;
 pub mod dbus;
+pub mod tls;
+pub mod socket;
+pub mod http;
 use crate::error::OtaErr;
-use serde_json::Value;
+use crate::protocol::{Request, TimerState};
+use std::fmt;
+use std::str::FromStr;
 
+// One relay of a RelayStatus.
+#[derive(Debug, Clone)]
+pub struct RelayResult {
+    pub relay: usize,
+    // the state it holds
+    pub on: bool,
+    // how the command went
+    pub result: Result<(), OtaErr>,
+    // the change still pending on it
+    pub timer: Option<TimerState>,
+    // the hash as sent when it names no relay; `relay` means nothing then
+    // and `result` is an error
+    pub hash: Option<String>,
+}
 
+// What the service reports. Every transport gets every message and ignores
+// the ones it has no use for.
+#[derive(Debug, Clone)]
 pub enum TransportIn {
-    
+    // outcome of a relay command, `on` is the state the relay holds now;
+    // every relay of the request being answered (`json_init`)
+    RelayStatus { relays: Vec<RelayResult>, json_init: Request },
+    // answer to a "get": every relay, in ios order, with its pending timer
+    Sync { relays: Vec<bool>, timers: Vec<Option<TimerState>> },
+    // same, but only refreshes local state (startup)
+    Relays(Vec<bool>),
+    KeepAlive,
+    Temperature(f32),
+    // every led, in leds order
+    Leds(Vec<LedMode>),
+    Fan { level: usize, pattern: Vec<u8> },
+    ButtonEvent { action: String },
+    // button action bound to an mqtt topic
+    Command { action: String, topic: String },
+    ActionDone { action: String, result: Result<(), OtaErr> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedMode {
+    On,
+    Off,
+    // period in ms
+    Blink { fre: u16 },
+}
+
+impl fmt::Display for LedMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedMode::On => write!(f, "on"),
+            LedMode::Off => write!(f, "off"),
+            LedMode::Blink { .. } => write!(f, "blink"),
+        }
+    }
+}
+
+impl FromStr for LedMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "on" => Ok(LedMode::On),
+            "off" => Ok(LedMode::Off),
+            "blink" => Ok(LedMode::Blink { fre: 1000 }),
+            _ => Err(format!("unknown led mode {:?}, expected on, off or blink", s)),
+        }
+    }
+}
+
+// Outbound message kinds, each with its own QoS / retain (mqtt.publish).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageClass {
+    Config,
+    Status,
+    KeepAlive,
+    Event,
+    Command,
+    // replies to rejected commands
+    Error,
 }
 
 #[derive(Clone)]
 pub enum TransportOut {
-    ResponseMqttEvent(Value),
+    ResponseMqttEvent(Request),
+    // broker link up (after every ConnAck) or lost
+    Connected,
+    Disconnected,
+    // direct commands from a local transport
+    SetRelay { relay: usize, on: bool },
+    SetLed { led: u64, mode: LedMode },
 }
 
 #[async_trait::async_trait]
-pub trait Transport {
+pub trait Transport: Send {
+    fn name(&self) -> &'static str;
     async fn send(&mut self, data: TransportIn) -> Result<(), OtaErr>;
+    // must be cancel safe, it runs inside select!
     async fn recv(&mut self) -> Result<TransportOut, OtaErr>;
+    async fn shutdown(&mut self) {}
+}
+
+// Every enabled transport: sends go to all of them, recv yields whichever
+// has something first.
+#[derive(Default)]
+pub struct Transports {
+    inner: Vec<Box<dyn Transport>>,
+}
+
+impl Transports {
+    pub fn new(inner: Vec<Box<dyn Transport>>) -> Self {
+        Transports { inner }
+    }
+
+    pub fn push(&mut self, transport: Box<dyn Transport>) {
+        self.inner.push(transport);
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.inner.iter().map(|t| t.name()).collect()
+    }
+
+    // Every transport gets the message even if an earlier one failed; the
+    // first error is returned.
+    pub async fn send(&mut self, data: TransportIn) -> Result<(), OtaErr> {
+        let mut result = Ok(());
+        for transport in self.inner.iter_mut() {
+            if let Err(e) = transport.send(data.clone()).await {
+                log::error!("{}: send: {:?}", transport.name(), e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    pub async fn recv(&mut self) -> Result<TransportOut, OtaErr> {
+        if self.inner.is_empty() {
+            return std::future::pending().await;
+        }
+        let (result, index, _) = futures::future::select_all(self.inner.iter_mut().map(|t| t.recv())).await;
+        if let Err(e) = &result {
+            log::error!("{}: recv: {:?}", self.inner[index].name(), e);
+        }
+        result
+    }
+
+    pub async fn shutdown(&mut self) {
+        for transport in self.inner.iter_mut() {
+            transport.shutdown().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Fake {
+        name: &'static str,
+        sent: Arc<Mutex<Vec<&'static str>>>,
+        fail: bool,
+        out: Option<TransportOut>,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for Fake {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        async fn send(&mut self, _data: TransportIn) -> Result<(), OtaErr> {
+            self.sent.lock().unwrap().push(self.name);
+            match self.fail {
+                true => Err(OtaErr::MqttErr),
+                false => Ok(()),
+            }
+        }
+
+        async fn recv(&mut self) -> Result<TransportOut, OtaErr> {
+            match self.out.take() {
+                Some(out) => Ok(out),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut transports = Transports::new(vec![
+            Box::new(Fake { name: "a", sent: sent.clone(), fail: true, out: None }),
+            Box::new(Fake { name: "b", sent: sent.clone(), fail: false, out: Some(TransportOut::Connected) }),
+        ]);
+
+        assert_eq!(transports.send(TransportIn::KeepAlive).await, Err(OtaErr::MqttErr));
+        assert_eq!(*sent.lock().unwrap(), vec!["a", "b"]);
+        assert!(matches!(transports.recv().await, Ok(TransportOut::Connected)));
+
+        let idle = tokio::time::timeout(std::time::Duration::from_millis(20), transports.recv()).await;
+        assert!(idle.is_err());
+    }
 }
 
@@ -1,68 +1,224 @@
-// use dbus_tokio::connection::{self, IOResource};
-// use dbus::{blocking::SyncConnection, nonblock};
-// use std::{sync::Arc, time::Duration};
-// use dbus::message::MatchRule;
-// use super::TransportOut;
-// use tokio::sync::mpsc;
-// use crate::error::OtaErr;
-
-
-// pub struct DbusDriver {
-//     pub tx: mpsc::Sender<Result<TransportOut, OtaErr>>,
-//     pub rx: mpsc::Receiver<Result<TransportOut, OtaErr>>,
-//     pub conn: Arc<dbus::nonblock::SyncConnection>,
-// }
-
-
-// impl DbusDriver {
-//     pub async fn new() -> DbusDriver {
-//         let (tx, rx) = mpsc::channel::<Result<TransportOut, OtaErr>>(2);
-//         let (resource, conn) = connection::new_session_sync().unwrap();
-//         tokio::spawn(async move {
-//             let err = resource.await;
-//             panic!("Lost connection to D-Bus: {}", err);
-//         });
-//         DbusDriver {
-//             tx: tx,
-//             rx: rx,
-//             conn: conn,
-//         }
-//     }
-//     pub async fn send(&mut self)-> Result<(),OtaErr> {        
-//         // Create interval - a Stream that will fire an event periodically
-//         let mut interval = tokio::time::interval(Duration::from_secs(2));
-    
-//         // Create a future calling D-Bus method each time the interval generates a tick
-//         let conn2 = self.conn.clone();
-//         let calls = async move {
-//                 interval.tick().await;
-//                 let conn = conn2.clone();
-    
-//                 log::info!("Calling Hello...");
-//                 let proxy = nonblock::Proxy::new("com.example.dbustest", "/hello", Duration::from_secs(2), conn);
-//                 // TODO: Handle timeouts and errors here
-//                 let (x,): (String,) = proxy.method_call("com.example.dbustest", "Hello", ("Tokio async/await",)).await.unwrap();
-//                 log::info!("{}", x);
-//         };
-    
-//         // To receive D-Bus signals we need to add a match that defines which signals should be forwarded
-//         // to our application.
-//         let mr = MatchRule::new_signal("com.example.dbustest", "HelloHappened");
-//         let incoming_signal = self.conn.add_match(mr).await.unwrap().cb(|_, (source,): (String,)| {
-//             log::info!("Hello from {} happened on the bus!", source);
-//             true
-//         });
-    
-//         // This will never return (except on panic) as there's no exit condition in the calls loop
-//         calls.await;
-    
-//         // Needed here to ensure the "incoming_signal" object is not dropped too early
-//         self.conn.remove_match(incoming_signal.token()).await.unwrap();
-
-//         Ok(())
-//     }
-
-//     pub async fn recv(&mut self) -> Result<TransportOut, OtaErr> {
-//         return self.rx.recv().await.unwrap();
-//     }
-// }
\ No newline at end of file
+use std::ffi::CString;
+use std::sync::{Arc, Mutex};
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::{MatchRule, MessageType};
+use dbus::nonblock::SyncConnection;
+use dbus::Message;
+use dbus_tokio::connection;
+use tokio::sync::mpsc;
+use super::{LedMode, Transport, TransportIn, TransportOut};
+use crate::error::OtaErr;
+
+pub const PATH: &str = "/org/lumi/IoService";
+pub const INTERFACE: &str = "org.lumi.IoService";
+
+const INTROSPECT: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.lumi.IoService">
+    <method name="SetRelay">
+      <arg name="relay" type="u" direction="in"/>
+      <arg name="on" type="b" direction="in"/>
+    </method>
+    <method name="GetRelays">
+      <arg name="states" type="ab" direction="out"/>
+    </method>
+    <method name="SetLed">
+      <arg name="led" type="u" direction="in"/>
+      <arg name="mode" type="s" direction="in"/>
+    </method>
+    <method name="GetTemperature">
+      <arg name="celsius" type="d" direction="out"/>
+    </method>
+    <signal name="RelayChanged">
+      <arg name="relay" type="u"/>
+      <arg name="on" type="b"/>
+    </signal>
+    <signal name="ButtonEvent">
+      <arg name="action" type="s"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+// What the Get* methods answer, kept up to date through `send`.
+#[derive(Debug, Default)]
+pub struct DbusState {
+    pub relays: Vec<bool>,
+    pub temperature: Option<f32>,
+}
+
+fn error(msg: &Message, name: &str, text: String) -> Message {
+    let text = CString::new(text).unwrap_or_default();
+    msg.error(&name.into(), &text)
+}
+
+// Answer one method call. Commands are forwarded on `tx` and acknowledged
+// right away; their outcome shows up as RelayChanged or on the LEDs.
+pub fn dispatch(msg: &Message, state: &Mutex<DbusState>, tx: &mpsc::Sender<Result<TransportOut, OtaErr>>) -> Message {
+    let interface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+    let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+    let invalid = "org.freedesktop.DBus.Error.InvalidArgs";
+
+    let forward = |out: TransportOut| match tx.try_send(Ok(out)) {
+        Ok(()) => msg.method_return(),
+        Err(_) => error(msg, "org.lumi.IoService.Error.Busy", "too many pending commands".to_string()),
+    };
+
+    match (interface.as_str(), member.as_str()) {
+        ("org.freedesktop.DBus.Introspectable", "Introspect") => msg.method_return().append1(INTROSPECT),
+        (INTERFACE, "SetRelay") => match msg.read2::<u32, bool>() {
+            Ok((relay, on)) => {
+                let count = state.lock().unwrap().relays.len();
+                if relay as usize >= count {
+                    return error(msg, invalid, format!("relay {} out of range, {} relays", relay, count));
+                }
+                forward(TransportOut::SetRelay { relay: relay as usize, on })
+            }
+            Err(e) => error(msg, invalid, e.to_string()),
+        },
+        (INTERFACE, "GetRelays") => msg.method_return().append1(state.lock().unwrap().relays.clone()),
+        (INTERFACE, "SetLed") => match msg.read2::<u32, &str>() {
+            Ok((led, mode)) => match mode.parse::<LedMode>() {
+                Ok(mode) => forward(TransportOut::SetLed { led: led as u64, mode }),
+                Err(e) => error(msg, invalid, e),
+            },
+            Err(e) => error(msg, invalid, e.to_string()),
+        },
+        (INTERFACE, "GetTemperature") => match state.lock().unwrap().temperature {
+            Some(temp) => msg.method_return().append1(temp as f64),
+            None => error(msg, "org.lumi.IoService.Error.Unavailable", "no temperature reading yet".to_string()),
+        },
+        _ => error(msg, "org.freedesktop.DBus.Error.UnknownMethod", format!("unknown method {}.{}", interface, member)),
+    }
+}
+
+// Exports org.lumi.IoService on the system (or session) bus so local daemons
+// can drive the IO without a broker.
+pub struct DbusDriver {
+    conn: Arc<SyncConnection>,
+    state: Arc<Mutex<DbusState>>,
+    rx: mpsc::Receiver<Result<TransportOut, OtaErr>>,
+}
+
+impl DbusDriver {
+    pub async fn new(session: bool, name: &str) -> Result<DbusDriver, OtaErr> {
+        let (resource, conn) = match session {
+            true => connection::new_session_sync(),
+            false => connection::new_system_sync(),
+        }
+        .map_err(|e| {
+            log::error!("dbus connect: {}", e);
+            OtaErr::DbusErr
+        })?;
+
+        let (tx, rx) = mpsc::channel::<Result<TransportOut, OtaErr>>(16);
+        let lost = tx.clone();
+        tokio::spawn(async move {
+            let err = resource.await;
+            log::error!("Lost connection to D-Bus: {}", err);
+            let _ = lost.send(Err(OtaErr::DbusErr)).await;
+        });
+
+        conn.request_name(name, false, true, true).await.map_err(|e| {
+            log::error!("dbus request name {}: {}", name, e);
+            OtaErr::DbusErr
+        })?;
+
+        let state = Arc::new(Mutex::new(DbusState::default()));
+        let calls = state.clone();
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::MethodCall);
+        rule.path = Some(PATH.into());
+        conn.start_receive(rule, Box::new(move |msg, conn| {
+            let reply = dispatch(&msg, &calls, &tx);
+            if !msg.get_no_reply() && conn.send(reply).is_err() {
+                log::error!("dbus: reply to {:?} failed", msg.member());
+            }
+            true
+        }));
+        log::info!("dbus: {} exported at {}", name, PATH);
+
+        Ok(DbusDriver { conn, state, rx })
+    }
+
+    fn signal(&self, name: &str, build: impl FnOnce(Message) -> Message) -> Result<(), OtaErr> {
+        let signal = Message::new_signal(PATH, INTERFACE, name).map_err(|_| OtaErr::DbusErr)?;
+        self.conn.send(build(signal)).map(|_| ()).map_err(|_| OtaErr::DbusErr)
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for DbusDriver {
+    fn name(&self) -> &'static str {
+        "dbus"
+    }
+
+    async fn send(&mut self, data: TransportIn) -> Result<(), OtaErr> {
+        match data {
+            TransportIn::Relays(relays) | TransportIn::Sync { relays, .. } => self.state.lock().unwrap().relays = relays,
+            TransportIn::RelayStatus { relays, .. } => {
+                for r in relays.into_iter().filter(|r| r.result.is_ok()) {
+                    // delayed and cancelled commands leave the relay as it is
+                    let previous = self.state.lock().unwrap().relays.get_mut(r.relay).map(|state| std::mem::replace(state, r.on));
+                    if previous != Some(r.on) {
+                        self.signal("RelayChanged", |s| s.append2(r.relay as u32, r.on))?;
+                    }
+                }
+            }
+            TransportIn::Temperature(temp) => self.state.lock().unwrap().temperature = Some(temp),
+            TransportIn::ButtonEvent { action } => self.signal("ButtonEvent", |s| s.append1(action))?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<TransportOut, OtaErr> {
+        match self.rx.recv().await {
+            Some(out) => out,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call(method: &str) -> Message {
+        let mut msg = Message::new_method_call("org.lumi.IoService", PATH, INTERFACE, method).unwrap();
+        msg.set_serial(1);
+        msg
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let state = Mutex::new(DbusState { relays: vec![false, true], temperature: None });
+        let (tx, mut rx) = mpsc::channel(4);
+
+        let reply = dispatch(&call("GetRelays"), &state, &tx);
+        assert_eq!(reply.read1::<Vec<bool>>().unwrap(), vec![false, true]);
+
+        let reply = dispatch(&call("SetRelay").append2(1u32, false), &state, &tx);
+        assert_eq!(reply.msg_type(), MessageType::MethodReturn);
+        assert!(matches!(rx.try_recv(), Ok(Ok(TransportOut::SetRelay { relay: 1, on: false }))));
+
+        let reply = dispatch(&call("SetRelay").append2(5u32, true), &state, &tx);
+        assert_eq!(reply.msg_type(), MessageType::Error);
+
+        let reply = dispatch(&call("SetLed").append2(0u32, "blink"), &state, &tx);
+        assert_eq!(reply.msg_type(), MessageType::MethodReturn);
+        assert!(matches!(rx.try_recv(), Ok(Ok(TransportOut::SetLed { led: 0, mode: LedMode::Blink { .. } }))));
+        let reply = dispatch(&call("SetLed").append2(0u32, "pulse"), &state, &tx);
+        assert_eq!(reply.msg_type(), MessageType::Error);
+
+        assert_eq!(dispatch(&call("GetTemperature"), &state, &tx).msg_type(), MessageType::Error);
+        state.lock().unwrap().temperature = Some(48.5);
+        assert_eq!(dispatch(&call("GetTemperature"), &state, &tx).read1::<f64>().unwrap(), 48.5);
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout, Duration, Instant};
+use super::{LedMode, Transport, TransportIn, TransportOut};
+use crate::error::OtaErr;
+use crate::json::relay_command_value;
+
+// how long PUT /relays/{n} waits for the relay status
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Reply = (StatusCode, Json<Value>);
+// the state the relay holds and how the command went
+type RelayDone = oneshot::Sender<(bool, Result<(), OtaErr>)>;
+
+#[derive(Default)]
+struct HttpState {
+    relays: Vec<bool>,
+    leds: Vec<LedMode>,
+    temperature: Option<f32>,
+    fan: Option<(usize, Vec<u8>)>,
+    // relay commands waiting for their status, by reqid, with the relay
+    pending: HashMap<String, (usize, RelayDone)>,
+}
+
+#[derive(Clone)]
+struct Shared {
+    state: Arc<Mutex<HttpState>>,
+    tx: mpsc::Sender<Result<TransportOut, OtaErr>>,
+    mac_id: Arc<str>,
+    started: Instant,
+}
+
+#[derive(Deserialize)]
+struct RelayBody {
+    on: bool,
+}
+
+#[derive(Deserialize)]
+struct LedBody {
+    mode: String,
+    // blink period in ms
+    fre: Option<u16>,
+}
+
+fn error(status: StatusCode, message: String) -> Reply {
+    (status, Json(json!({ "error": message })))
+}
+
+fn led_value(led: usize, mode: &LedMode) -> Value {
+    let mut value = json!({ "led": led, "mode": mode.to_string() });
+    if let LedMode::Blink { fre } = mode {
+        value["fre"] = json!(fre);
+    }
+    value
+}
+
+// A refused interlock or an unknown relay is the caller's problem, anything
+// else a fault of the unit.
+fn relay_error_status(err: &OtaErr) -> StatusCode {
+    match err {
+        OtaErr::InterlockErr => StatusCode::CONFLICT,
+        OtaErr::SelectPinErr => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn get_relays(State(shared): State<Shared>) -> Json<Value> {
+    let relays = shared.state.lock().unwrap().relays.clone();
+    let relays: Vec<Value> = relays.iter().enumerate().map(|(relay, on)| json!({ "relay": relay, "on": on })).collect();
+    Json(json!({ "relays": relays }))
+}
+
+// Goes through OtaLogic like an MQTT "set" and answers with the relay status.
+async fn put_relay(State(shared): State<Shared>, Path(relay): Path<usize>, Json(body): Json<RelayBody>) -> Reply {
+    let reqid: String = rand::thread_rng().sample_iter(rand::distributions::Alphanumeric).take(12).map(char::from).collect();
+    let (done_tx, done_rx) = oneshot::channel();
+    {
+        let mut state = shared.state.lock().unwrap();
+        if relay >= state.relays.len() {
+            return error(StatusCode::NOT_FOUND, format!("relay {} out of range, {} relays", relay, state.relays.len()));
+        }
+        state.pending.insert(reqid.clone(), (relay, done_tx));
+    }
+
+    let command = relay_command_value(&shared.mac_id, relay, body.on, &reqid, "http");
+    if shared.tx.try_send(Ok(TransportOut::ResponseMqttEvent(command))).is_err() {
+        shared.state.lock().unwrap().pending.remove(&reqid);
+        return error(StatusCode::SERVICE_UNAVAILABLE, "too many pending commands".to_string());
+    }
+
+    match timeout(RELAY_TIMEOUT, done_rx).await {
+        Ok(Ok((on, Ok(())))) => (StatusCode::OK, Json(json!({ "relay": relay, "on": on }))),
+        Ok(Ok((on, Err(e)))) => (relay_error_status(&e), Json(json!({ "relay": relay, "on": on, "error": e.reason() }))),
+        _ => {
+            shared.state.lock().unwrap().pending.remove(&reqid);
+            error(StatusCode::GATEWAY_TIMEOUT, format!("no status for relay {}", relay))
+        }
+    }
+}
+
+async fn get_leds(State(shared): State<Shared>) -> Json<Value> {
+    let leds = shared.state.lock().unwrap().leds.clone();
+    let leds: Vec<Value> = leds.iter().enumerate().map(|(led, mode)| led_value(led, mode)).collect();
+    Json(json!({ "leds": leds }))
+}
+
+async fn put_led(State(shared): State<Shared>, Path(led): Path<usize>, Json(body): Json<LedBody>) -> Reply {
+    let mode = match body.mode.parse::<LedMode>() {
+        Ok(LedMode::Blink { fre }) => {
+            let fre = body.fre.unwrap_or(fre);
+            // the blink runs on the 100 ms tick
+            if !(100..=10_000).contains(&fre) {
+                return error(StatusCode::BAD_REQUEST, format!("fre {} out of range, 100..=10000 ms", fre));
+            }
+            LedMode::Blink { fre }
+        }
+        Ok(mode) => mode,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    let count = shared.state.lock().unwrap().leds.len();
+    if led >= count {
+        return error(StatusCode::NOT_FOUND, format!("led {} out of range, {} leds", led, count));
+    }
+    if shared.tx.try_send(Ok(TransportOut::SetLed { led: led as u64, mode })).is_err() {
+        return error(StatusCode::SERVICE_UNAVAILABLE, "too many pending commands".to_string());
+    }
+    (StatusCode::ACCEPTED, Json(led_value(led, &mode)))
+}
+
+async fn get_temperature(State(shared): State<Shared>) -> Reply {
+    match shared.state.lock().unwrap().temperature {
+        Some(temp) => (StatusCode::OK, Json(json!({ "celsius": (temp as f64 * 10.0).round() / 10.0 }))),
+        None => error(StatusCode::SERVICE_UNAVAILABLE, "no temperature reading yet".to_string()),
+    }
+}
+
+async fn get_fan(State(shared): State<Shared>) -> Json<Value> {
+    match &shared.state.lock().unwrap().fan {
+        Some((level, pattern)) => Json(json!({ "level": level, "pattern": pattern })),
+        None => Json(json!({ "level": null, "pattern": [] })),
+    }
+}
+
+async fn get_health(State(shared): State<Shared>) -> Json<Value> {
+    Json(json!({
+        "status": "ok",
+        "id": &*shared.mac_id,
+        "uptime_s": shared.started.elapsed().as_secs(),
+    }))
+}
+
+// Small REST API for checking a unit with curl. Relay and LED commands are
+// fed to OtaLogic like any other transport.
+pub struct HttpDriver {
+    addr: SocketAddr,
+    state: Arc<Mutex<HttpState>>,
+    rx: mpsc::Receiver<Result<TransportOut, OtaErr>>,
+}
+
+impl HttpDriver {
+    pub async fn new(listen: SocketAddr, mac_id: &str) -> Result<HttpDriver, OtaErr> {
+        let listener = tokio::net::TcpListener::bind(listen).await.map_err(|e| {
+            log::error!("http bind {}: {}", listen, e);
+            OtaErr::HttpErr
+        })?;
+        let addr = listener.local_addr().map_err(|_| OtaErr::HttpErr)?;
+
+        let state = Arc::new(Mutex::new(HttpState::default()));
+        let (tx, rx) = mpsc::channel::<Result<TransportOut, OtaErr>>(16);
+        let shared = Shared { state: state.clone(), tx: tx.clone(), mac_id: mac_id.into(), started: Instant::now() };
+        let router = Router::new()
+            .route("/relays", get(get_relays))
+            .route("/relays/:n", axum::routing::put(put_relay))
+            .route("/leds", get(get_leds))
+            .route("/leds/:n", axum::routing::put(put_led))
+            .route("/temperature", get(get_temperature))
+            .route("/fan", get(get_fan))
+            .route("/health", get(get_health))
+            .with_state(shared);
+
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, router).await {
+                log::error!("http server: {}", e);
+                let _ = tx.send(Err(OtaErr::HttpErr)).await;
+            }
+        });
+        log::info!("http: listening on {}", addr);
+
+        Ok(HttpDriver { addr, state, rx })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for HttpDriver {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    async fn send(&mut self, data: TransportIn) -> Result<(), OtaErr> {
+        let mut state = self.state.lock().unwrap();
+        match data {
+            TransportIn::Relays(relays) | TransportIn::Sync { relays, .. } => state.relays = relays,
+            TransportIn::RelayStatus { relays, json_init } => {
+                for r in &relays {
+                    if let (true, Some(current)) = (r.result.is_ok(), state.relays.get_mut(r.relay)) {
+                        *current = r.on;
+                    }
+                }
+                // PUT /relays/{n} sends one relay per command, the status may
+                // also carry interlocked relays it switched off
+                if let Some((relay, done)) = state.pending.remove(&json_init.reqid) {
+                    if let Some(r) = relays.into_iter().find(|r| r.relay == relay && r.hash.is_none()) {
+                        let _ = done.send((r.on, r.result));
+                    }
+                }
+            }
+            TransportIn::Leds(leds) => state.leds = leds,
+            TransportIn::Temperature(temp) => state.temperature = Some(temp),
+            TransportIn::Fan { level, pattern } => state.fan = Some((level, pattern)),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<TransportOut, OtaErr> {
+        match self.rx.recv().await {
+            Some(out) => out,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::RelayResult;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: io\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method, path, body.len(), body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_http_api() {
+        let mut driver = HttpDriver::new("127.0.0.1:0".parse().unwrap(), "AB12").await.unwrap();
+        let addr = driver.local_addr();
+        driver.send(TransportIn::Relays(vec![false, false])).await.unwrap();
+        driver.send(TransportIn::Leds(vec![LedMode::On, LedMode::Blink { fre: 500 }])).await.unwrap();
+
+        let (status, body) = request(addr, "GET", "/leds", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["leds"][1], json!({"led": 1, "mode": "blink", "fre": 500}));
+        assert_eq!(request(addr, "GET", "/temperature", "").await.0, 503);
+        assert_eq!(request(addr, "PUT", "/relays/7", r#"{"on":true}"#).await.0, 404);
+        assert_eq!(request(addr, "PUT", "/leds/0", r#"{"mode":"blink","fre":20}"#).await.0, 400);
+
+        let put = tokio::spawn(request(addr, "PUT", "/relays/1", r#"{"on":true}"#));
+        let json_init = match driver.recv().await {
+            Ok(TransportOut::ResponseMqttEvent(command)) => command,
+            _ => panic!("expected the relay command"),
+        };
+        assert_eq!(json_init.hash(), Some("io-AB12-1"));
+        driver.send(TransportIn::RelayStatus { relays: vec![RelayResult { relay: 1, on: true, result: Ok(()), timer: None, hash: None }], json_init }).await.unwrap();
+        assert_eq!(put.await.unwrap(), (200, json!({"relay": 1, "on": true})));
+
+        let (_, body) = request(addr, "GET", "/relays", "").await;
+        assert_eq!(body["relays"][1]["on"], true);
+        assert_eq!(request(addr, "GET", "/health", "").await.1["id"], "AB12");
+    }
+
+    #[tokio::test]
+    async fn test_put_relay_answers_for_that_relay() {
+        let mut driver = HttpDriver::new("127.0.0.1:0".parse().unwrap(), "AB12").await.unwrap();
+        let addr = driver.local_addr();
+        driver.send(TransportIn::Relays(vec![false, true])).await.unwrap();
+
+        // with 0+1:exclusive the partner switched off comes first in the status
+        let put = tokio::spawn(request(addr, "PUT", "/relays/0", r#"{"on":true}"#));
+        let json_init = match driver.recv().await {
+            Ok(TransportOut::ResponseMqttEvent(command)) => command,
+            _ => panic!("expected the relay command"),
+        };
+        let relays = vec![
+            RelayResult { relay: 1, on: false, result: Ok(()), timer: None, hash: None },
+            RelayResult { relay: 0, on: true, result: Ok(()), timer: None, hash: None },
+        ];
+        driver.send(TransportIn::RelayStatus { relays, json_init }).await.unwrap();
+        assert_eq!(put.await.unwrap(), (200, json!({"relay": 0, "on": true})));
+
+        let (_, body) = request(addr, "GET", "/relays", "").await;
+        assert_eq!(body["relays"], json!([{"relay": 0, "on": true}, {"relay": 1, "on": false}]));
+    }
+
+    #[tokio::test]
+    async fn test_put_relay_refused_by_interlock() {
+        let mut driver = HttpDriver::new("127.0.0.1:0".parse().unwrap(), "AB12").await.unwrap();
+        let addr = driver.local_addr();
+        driver.send(TransportIn::Relays(vec![false, true])).await.unwrap();
+
+        // 0+1:forbid with relay 1 on
+        let put = tokio::spawn(request(addr, "PUT", "/relays/0", r#"{"on":true}"#));
+        let json_init = match driver.recv().await {
+            Ok(TransportOut::ResponseMqttEvent(command)) => command,
+            _ => panic!("expected the relay command"),
+        };
+        let relays = vec![RelayResult { relay: 0, on: false, result: Err(OtaErr::InterlockErr), timer: None, hash: None }];
+        driver.send(TransportIn::RelayStatus { relays, json_init }).await.unwrap();
+        assert_eq!(put.await.unwrap(), (409, json!({"relay": 0, "on": false, "error": "interlocked"})));
+    }
+}
@@ -1,90 +1,484 @@
-use rumqttc::{MqttOptions, AsyncClient, EventLoop, Event, QoS};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use rumqttc::{MqttOptions, AsyncClient, EventLoop, Event, LastWill, Packet, QoS, TlsConfiguration};
+use rand::Rng;
 use crate::error::OtaErr;
-use tokio::time::Duration;
-use super::TransportOut;
-use tokio::sync::mpsc;
-use serde_json::Value;
+use tokio::time::{sleep_until, Duration, Instant};
+use super::{MessageClass, Transport, TransportIn, TransportOut};
+use crate::error::ProtocolErr;
+use crate::protocol::{reqid_of, Request, SOURCE};
+use crate::config::{MqttConfig, PublishConfig, Topics};
+use crate::json::{error_value, presence_value, JsonDriver, JsonIn};
 
+// Exponential reconnect delay, each step drawn from [delay/2, delay] so a
+// fleet restarted together does not hit the broker in lockstep.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff { min, max, current: min }
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+
+    pub fn delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0..=half))
+    }
+}
+
+fn qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+struct Outgoing {
+    class: Option<MessageClass>,
+    topic: String,
+    message: Vec<u8>,
+    qos: QoS,
+    retain: bool,
+}
+
+// A QoS 1/2 publish waiting for its PUBACK / PUBCOMP.
+struct Tracked {
+    class: Option<MessageClass>,
+    topic: String,
+    since: Instant,
+    warned: bool,
+}
+
+// Matches publishes handed to the client with the packet ids the event loop
+// gives them, then with the acks. The client hands them on in order, so the
+// n-th Outgoing::Publish event belongs to the n-th handed publish.
+#[derive(Default)]
+pub struct AckTracker {
+    handed: VecDeque<Option<Tracked>>,
+    inflight: HashMap<u16, Tracked>,
+    pub acked: u64,
+    pub unacked: u64,
+}
+
+impl AckTracker {
+    fn handed(&mut self, outgoing: &Outgoing, now: Instant) {
+        let tracked = (outgoing.qos != QoS::AtMostOnce).then(|| Tracked {
+            class: outgoing.class,
+            topic: outgoing.topic.clone(),
+            since: now,
+            warned: false,
+        });
+        self.handed.push_back(tracked);
+    }
+
+    fn sent(&mut self, pkid: u16) {
+        // publishes resent after a reconnect keep their packet id
+        if pkid != 0 && self.inflight.contains_key(&pkid) {
+            return;
+        }
+        if let Some(Some(tracked)) = self.handed.pop_front() {
+            self.inflight.insert(pkid, tracked);
+        }
+    }
+
+    fn acked(&mut self, pkid: u16, now: Instant) {
+        if let Some(tracked) = self.inflight.remove(&pkid) {
+            self.acked += 1;
+            log::debug!("mqtt {:?} {} acked after {} ms", tracked.class, tracked.topic, now.duration_since(tracked.since).as_millis());
+        }
+    }
+
+    // Report publishes still unacked after `timeout`; they stay tracked since
+    // the client keeps retrying them.
+    fn expire(&mut self, timeout: Duration, now: Instant) {
+        for (pkid, tracked) in self.inflight.iter_mut() {
+            if !tracked.warned && now.duration_since(tracked.since) >= timeout {
+                tracked.warned = true;
+                self.unacked += 1;
+                log::warn!("mqtt {:?} {} (pkid {}) not acked after {} ms", tracked.class, tracked.topic, pkid, timeout.as_millis());
+            }
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.inflight.len()
+    }
+}
+
+// how often a rejected command of one reason is logged
+const REJECT_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+// Rejected commands by reason. A flood of bad input logs one warning per
+// reason and interval, with the number left out.
+#[derive(Default)]
+pub struct Rejects {
+    counts: HashMap<&'static str, u64>,
+    // last warning and how many were not logged since
+    logged: HashMap<&'static str, (Instant, u64)>,
+}
+
+impl Rejects {
+    // Counts one, Some(skipped since the last warning) when this one is to
+    // be logged.
+    fn record(&mut self, reason: &'static str, now: Instant) -> Option<u64> {
+        *self.counts.entry(reason).or_default() += 1;
+        match self.logged.get_mut(reason) {
+            Some((last, skipped)) if now.duration_since(*last) < REJECT_LOG_INTERVAL => {
+                *skipped += 1;
+                None
+            }
+            Some((last, skipped)) => {
+                let was = *skipped;
+                *last = now;
+                *skipped = 0;
+                Some(was)
+            }
+            None => {
+                self.logged.insert(reason, (now, 0));
+                Some(0)
+            }
+        }
+    }
+
+    pub fn count(&self, reason: &str) -> u64 {
+        self.counts.get(reason).copied().unwrap_or(0)
+    }
+}
 
 pub struct MqttDriver {
-    pub tx: mpsc::Sender<Result<TransportOut, OtaErr>>,
-    pub rx: mpsc::Receiver<Result<TransportOut, OtaErr>>,
-    pub options: MqttOptions, 
+    pub options: MqttOptions,
     pub client: AsyncClient,
     pub eventloop: EventLoop,
-    pub flag:bool,
+    pub acks: AckTracker,
+    pub rejects: Rejects,
+    topics: Topics,
+    json: JsonDriver,
+    mac_id: String,
+    policies: PublishConfig,
+    ack_timeout: Duration,
+    connected: bool,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+    // published while offline, replayed after the next ConnAck
+    queue: VecDeque<Outgoing>,
+    queue_len: usize,
 }
 
-impl MqttDriver { 
-    pub async fn new(id:String, host:String, port: u16, keep_alive:u64) -> Self {
-        let mut mqttoptions = MqttOptions::new(id, host, port);
+impl MqttDriver {
+    pub async fn new(config: &MqttConfig, tls: Option<Arc<rustls::ClientConfig>>, mac_id: &str) -> Self {
+        let mut mqttoptions = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
 
-        mqttoptions.set_credentials("component", "123");
-        mqttoptions.set_keep_alive(Duration::from_secs(keep_alive));
+        if !config.username.is_empty() {
+            mqttoptions.set_credentials(config.username.clone(), config.password.clone());
+        }
+        if let Some(tls) = tls {
+            mqttoptions.set_transport(rumqttc::Transport::tls_with_config(TlsConfiguration::Rustls(tls)));
+        }
+        mqttoptions.set_keep_alive(Duration::from_secs(config.keep_alive_s));
 
-        let (client, eventloop) = AsyncClient::new(mqttoptions.clone(), 10);
+        let presence = config.topics.presence.clone();
+        if !presence.is_empty() {
+            let offline = presence_value(false, mac_id).to_string();
+            mqttoptions.set_last_will(LastWill::new(presence.clone(), offline, QoS::AtLeastOnce, true));
+        }
 
-        client.subscribe("component/io/+", QoS::AtMostOnce).await.unwrap();
+        let (client, eventloop) = AsyncClient::new(mqttoptions.clone(), 10);
 
-        let (tx, rx) = mpsc::channel::<Result<TransportOut, OtaErr>>(5);
         MqttDriver {
-            tx: tx,
-            rx: rx,
             options: mqttoptions.clone(),
-            client: client,
-            eventloop: eventloop, 
-            flag:false                                        
+            client,
+            eventloop,
+            acks: AckTracker::default(),
+            rejects: Rejects::default(),
+            topics: config.topics.clone(),
+            json: JsonDriver{},
+            mac_id: mac_id.to_string(),
+            policies: config.publish.clone(),
+            ack_timeout: Duration::from_millis(config.ack_timeout_ms),
+            connected: false,
+            backoff: Backoff::new(
+                Duration::from_millis(config.reconnect_min_ms),
+                Duration::from_millis(config.reconnect_max_ms),
+            ),
+            retry_at: None,
+            queue: VecDeque::new(),
+            queue_len: config.queue_len,
+        }
+    }
+
+    fn enqueue(&mut self, outgoing: Outgoing) -> Result<(), OtaErr> {
+        if self.queue_len == 0 {
+            log::warn!("mqtt offline, dropping message to {}", outgoing.topic);
+            return Err(OtaErr::MqttErr);
+        }
+        if self.queue.len() >= self.queue_len {
+            if let Some(dropped) = self.queue.pop_front() {
+                log::warn!("mqtt queue full, dropping message to {}", dropped.topic);
+            }
         }
+        self.queue.push_back(outgoing);
+        Ok(())
     }
-    pub async fn send(&mut self, topic: String, message: Vec<u8>, qos: QoS, retain: bool)-> Result<(),OtaErr> {
-        
-        log::info!("--> {} : {}", topic, String::from_utf8_lossy(&message).to_string());
 
-        match self.client.publish(topic, qos, retain, message).await {
-            Ok(res) => {
-                Ok(res)
+    // Give a publish to the client, false if its request channel is full.
+    fn hand(&mut self, outgoing: &Outgoing) -> bool {
+        let sent = self.client.try_publish(outgoing.topic.clone(), outgoing.qos, outgoing.retain, outgoing.message.clone());
+        if sent.is_ok() {
+            self.acks.handed(outgoing, Instant::now());
+        }
+        sent.is_ok()
+    }
+
+    // Hand queued messages to the client until its request channel is full.
+    fn flush(&mut self) {
+        while let Some(outgoing) = self.queue.pop_front() {
+            if !self.hand(&outgoing) {
+                self.queue.push_front(outgoing);
+                break;
+            }
+        }
+    }
+
+    fn publish_presence(&mut self, online: bool) {
+        if self.topics.presence.is_empty() {
+            return;
+        }
+        let outgoing = Outgoing {
+            class: None,
+            topic: self.topics.presence.clone(),
+            message: presence_value(online, &self.mac_id).to_string().into_bytes(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        };
+        if !self.hand(&outgoing) {
+            log::error!("mqtt presence: request channel full");
+        }
+    }
+
+    // Count and log a command that is not acted on, and tell the sender on
+    // topics.error.
+    fn reject(&mut self, topic: &str, payload: &[u8], reqid: String, err: ProtocolErr) {
+        if let Some(skipped) = self.rejects.record(err.reason(), Instant::now()) {
+            let payload = String::from_utf8_lossy(&payload[..payload.len().min(256)]);
+            match skipped {
+                0 => log::warn!("<-- {}: rejected, {}: {}", topic, err, payload),
+                _ => log::warn!("<-- {}: rejected, {}: {} ({} more {} not logged)", topic, err, payload, skipped, err.reason()),
+            }
+        }
+        if self.topics.error.is_empty() {
+            return;
+        }
+        let reply = error_value(&reqid, topic, &err).to_string();
+        if self.publish(MessageClass::Error, self.topics.error.clone(), reply).is_err() {
+            log::warn!("mqtt: error reply to {} dropped", topic);
+        }
+    }
+
+    // Publish with the QoS / retain of `class`. Messages that cannot go out
+    // now are queued; an error means the message was dropped.
+    fn publish(&mut self, class: MessageClass, topic: String, message: String) -> Result<(),OtaErr> {
+
+        log::info!("--> {} : {}", topic, message);
+
+        let policy = self.policies.policy(class);
+        let outgoing = Outgoing { class: Some(class), topic, message: message.into_bytes(), qos: qos(policy.qos), retain: policy.retain };
+        // keep the order: nothing overtakes what is already waiting
+        if !self.connected || !self.queue.is_empty() || !self.hand(&outgoing) {
+            return self.enqueue(outgoing);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for MqttDriver {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    async fn send(&mut self, data: TransportIn) -> Result<(), OtaErr> {
+        match data {
+            TransportIn::RelayStatus{relays, json_init} => {
+                let (mess, _) = self.json.convert(JsonIn::relay_status(json_init, &self.mac_id, relays)).await;
+                self.publish(MessageClass::Status, self.topics.status.clone(), mess)
+            }
+            TransportIn::Sync { relays, timers } => {
+                let (mess_sync, mess_st) = self.json.convert(JsonIn::SyncConvert{status: relays, timers, mac_id: self.mac_id.clone()}).await;
+                let config = self.publish(MessageClass::Config, self.topics.config.clone(), mess_sync);
+                let status = self.publish(MessageClass::Status, self.topics.status.clone(), mess_st);
+                config.and(status)
+            }
+            TransportIn::KeepAlive => {
+                let (mess, _) = self.json.convert(JsonIn::KeepAlive).await;
+                self.publish(MessageClass::KeepAlive, self.topics.keepalive.clone(), mess)
+            }
+            TransportIn::Command{action, topic} => {
+                let (mess, _) = self.json.convert(JsonIn::CommandConvert{cmd: action}).await;
+                self.publish(MessageClass::Command, topic, mess)
             }
-            Err(_) => {
-                Err(OtaErr::MqttErr)
+            TransportIn::ActionDone{action, result} => {
+                let (mess, _) = self.json.convert(JsonIn::EventConvert{action, result}).await;
+                self.publish(MessageClass::Event, self.topics.event.clone(), mess)
             }
+            TransportIn::Relays(_) | TransportIn::Temperature(_) | TransportIn::Leds(_) | TransportIn::Fan{..} | TransportIn::ButtonEvent{..} => Ok(()),
         }
     }
 
-    pub async fn recv(&mut self) -> Result<TransportOut, OtaErr> {
+    // Never fails: connection errors are retried here and reported as
+    // Connected / Disconnected transitions.
+    async fn recv(&mut self) -> Result<TransportOut, OtaErr> {
         loop {
-            let event = self.eventloop.poll().await;
-            match &event {
-                Ok(v) => {
-                    match v {
-                        Event::Incoming(packet) => {
-                            match packet {
-                                rumqttc::Packet::Publish(publish) => {
-                                    let payload_str: String = String::from_utf8_lossy(&publish.payload).to_string();
-                                    let parsed_json: Value = serde_json::from_str(&payload_str).unwrap();
-                                    let source = parsed_json["source"].as_str().unwrap();
-                                    if source == "io" {
-                                        
-                                    }
-                                    else {
-                                        self.tx.send(Ok(TransportOut::ResponseMqttEvent(parsed_json.clone()))).await.unwrap();
-                                        log::info!("<-- {}:{}",publish.topic ,payload_str);
-                                        return self.rx.recv().await.unwrap();
-                                    }
-                                }
-                                _ => {
-                                }
-                            }
+            if let Some(retry_at) = self.retry_at {
+                sleep_until(retry_at).await;
+                self.retry_at = None;
+            }
+            if self.connected {
+                self.flush();
+            }
+            self.acks.expire(self.ack_timeout, Instant::now());
+
+            match self.eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    log::info!("mqtt connected");
+                    self.backoff.reset();
+                    self.connected = true;
+                    // the session is clean, subscriptions do not survive a reconnect
+                    if let Err(e) = self.client.try_subscribe(self.topics.subscribe.clone(), QoS::AtMostOnce) {
+                        log::error!("mqtt subscribe {}: {}", self.topics.subscribe, e);
+                    }
+                    self.publish_presence(true);
+                    self.flush();
+                    return Ok(TransportOut::Connected);
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let parsed_json = match Request::parse(&publish.payload) {
+                        // our own status and errors come back on the wildcard
+                        Ok(parsed_json) if parsed_json.source == SOURCE => continue,
+                        Ok(parsed_json) if parsed_json.source.is_empty() => Err((parsed_json.reqid, ProtocolErr::Missing("source"))),
+                        Ok(parsed_json) => match parsed_json.validate() {
+                            Ok(()) => Ok(parsed_json),
+                            Err(e) => Err((parsed_json.reqid, e)),
+                        },
+                        Err(e) => Err((reqid_of(&publish.payload), e)),
+                    };
+                    match parsed_json {
+                        Ok(parsed_json) => {
+                            log::info!("<-- {}:{}",publish.topic ,String::from_utf8_lossy(&publish.payload));
+                            return Ok(TransportOut::ResponseMqttEvent(parsed_json));
                         }
-                        Event::Outgoing(_) => {}
+                        Err((reqid, e)) => self.reject(&publish.topic, &publish.payload, reqid, e),
                     }
                 }
+                Ok(Event::Outgoing(rumqttc::Outgoing::Publish(pkid))) => self.acks.sent(pkid),
+                Ok(Event::Incoming(Packet::PubAck(ack))) => self.acks.acked(ack.pkid, Instant::now()),
+                Ok(Event::Incoming(Packet::PubComp(comp))) => self.acks.acked(comp.pkid, Instant::now()),
+                Ok(_) => {}
                 Err(e) => {
-                    log::info!("Error = {e:?}");
-                    self.tx.send(Err(OtaErr::MqttErr)).await.unwrap();
-                    return self.rx.recv().await.unwrap();
+                    let delay = self.backoff.delay();
+                    log::warn!("mqtt: {:?}, retry in {} ms", e, delay.as_millis());
+                    self.retry_at = Some(Instant::now() + delay);
+                    if self.connected {
+                        self.connected = false;
+                        return Ok(TransportOut::Disconnected);
+                    }
+                }
+            }
+        }
+    }
+
+    // Replace the retained "online" with "offline" and disconnect cleanly so
+    // the broker does not send the last will.
+    async fn shutdown(&mut self) {
+        if !self.connected {
+            return;
+        }
+        self.publish_presence(false);
+        if let Err(e) = self.client.try_disconnect() {
+            log::error!("mqtt disconnect: {}", e);
+            return;
+        }
+        let drained = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match self.eventloop.poll().await {
+                    Ok(Event::Outgoing(rumqttc::Outgoing::Disconnect)) | Err(_) => break,
+                    Ok(_) => {}
                 }
             }
-            
+        })
+        .await;
+        if drained.is_err() {
+            log::warn!("mqtt: no clean disconnect within 2 s");
         }
+        self.connected = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+        for max in [100, 200, 400, 800, 1000, 1000] {
+            let delay = backoff.delay().as_millis() as u64;
+            assert!(delay >= max / 2 && delay <= max, "{} not in {}..={}", delay, max / 2, max);
+        }
+        backoff.reset();
+        assert!(backoff.delay() <= Duration::from_millis(100));
+    }
+
+    fn outgoing(topic: &str, qos: QoS) -> Outgoing {
+        Outgoing { class: Some(MessageClass::Status), topic: topic.to_string(), message: vec![], qos, retain: false }
+    }
+
+    #[test]
+    fn test_rejects() {
+        let mut rejects = Rejects::default();
+        let start = Instant::now();
+        assert_eq!(rejects.record("malformed", start), Some(0));
+        assert_eq!(rejects.record("malformed", start + Duration::from_secs(1)), None);
+        assert_eq!(rejects.record("malformed", start + Duration::from_secs(2)), None);
+        // another reason has its own interval
+        assert_eq!(rejects.record("unknown_cmd", start + Duration::from_secs(2)), Some(0));
+        assert_eq!(rejects.record("malformed", start + REJECT_LOG_INTERVAL), Some(2));
+        assert_eq!(rejects.record("malformed", start + REJECT_LOG_INTERVAL), None);
+        assert_eq!(rejects.count("malformed"), 5);
+        assert_eq!(rejects.count("unknown_cmd"), 1);
+        assert_eq!(rejects.count("missing_field"), 0);
+    }
+
+    #[test]
+    fn test_ack_tracker() {
+        let start = Instant::now();
+        let mut acks = AckTracker::default();
+        acks.handed(&outgoing("keepalive", QoS::AtMostOnce), start);
+        acks.handed(&outgoing("status/0", QoS::AtLeastOnce), start);
+        acks.handed(&outgoing("status/1", QoS::AtLeastOnce), start);
+
+        acks.sent(0);
+        acks.sent(1);
+        acks.sent(2);
+        assert_eq!(acks.pending(), 2);
+
+        acks.acked(1, start);
+        // pkid 2 is resent after a reconnect, it must not take a new slot
+        acks.sent(2);
+        acks.expire(Duration::from_secs(10), start + Duration::from_secs(11));
+        acks.expire(Duration::from_secs(10), start + Duration::from_secs(12));
+        assert_eq!((acks.acked, acks.unacked, acks.pending()), (1, 1, 1));
+
+        acks.acked(2, start + Duration::from_secs(13));
+        assert_eq!((acks.acked, acks.pending()), (2, 0));
     }
 }
@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use rand::Rng;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+use super::{Transport, TransportIn, TransportOut};
+use crate::error::OtaErr;
+use crate::protocol::Request;
+use crate::json::{local_event_value, reply_value, status_value, JsonDriver, JsonIn};
+
+// lines a client may lag behind before it is dropped
+const CLIENT_QUEUE: usize = 64;
+
+#[derive(Default)]
+struct Clients {
+    next_id: u64,
+    writers: HashMap<u64, mpsc::Sender<String>>,
+    subscribers: HashSet<u64>,
+    // relay commands waiting for their status, reqid -> client
+    pending: HashMap<String, u64>,
+    // answers "get" without going through OtaLogic
+    relays: Vec<bool>,
+    temperature: Option<f32>,
+}
+
+impl Clients {
+    fn write(&mut self, id: u64, line: String) {
+        if let Some(writer) = self.writers.get(&id) {
+            if writer.try_send(line).is_err() {
+                log::warn!("socket: client {} is not reading, dropping it", id);
+                self.remove(id);
+            }
+        }
+    }
+
+    fn broadcast(&mut self, line: String) {
+        let subscribers: Vec<u64> = self.subscribers.iter().copied().collect();
+        for id in subscribers {
+            self.write(id, line.clone());
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.writers.remove(&id);
+        self.subscribers.remove(&id);
+        self.pending.retain(|_, client| *client != id);
+    }
+}
+
+fn reqid() -> String {
+    rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect()
+}
+
+// One request line. Relay commands are answered later by their status, with
+// the same reqid; everything else is answered here.
+fn request(line: &str, id: u64, clients: &Mutex<Clients>, mac_id: &str, tx: &mpsc::Sender<Result<TransportOut, OtaErr>>) -> Option<String> {
+    let mut request = match Request::parse(line.as_bytes()) {
+        Ok(request) => request,
+        Err(e) => return Some(reply_value("", Some(&e.to_string())).to_string()),
+    };
+    if request.reqid.is_empty() {
+        request.reqid = reqid();
+    }
+    let reqid = request.reqid.clone();
+    let error = |reason: &str| Some(reply_value(&reqid, Some(reason)).to_string());
+    if matches!(request.cmd.as_str(), "set" | "get") {
+        if let Err(e) = request.validate() {
+            return error(&e.to_string());
+        }
+    }
+
+    let forward = |request: Request| tx.try_send(Ok(TransportOut::ResponseMqttEvent(request))).is_ok();
+
+    match request.cmd.as_str() {
+        "subscribe" => {
+            clients.lock().unwrap().subscribers.insert(id);
+            Some(reply_value(&reqid, None).to_string())
+        }
+        "get" if request.kind() == "temperature" => match clients.lock().unwrap().temperature {
+            Some(temp) => {
+                let mut reply = local_event_value("temperature", json!({ "celsius": (temp as f64 * 10.0).round() / 10.0 }));
+                reply.cmd = "status".to_string();
+                reply.reqid = reqid.clone();
+                Some(reply.to_string())
+            }
+            None => error("no temperature reading yet"),
+        },
+        "get" => {
+            let relays = clients.lock().unwrap().relays.clone();
+            let pin: Vec<(bool, String)> = relays.iter().enumerate().map(|(i, on)| (*on, format!("io-{}-{}", mac_id, i))).collect();
+            Some(status_value(&request, &pin).to_string())
+        }
+        "set" if request.control_source.is_some() => {
+            // OtaLogic drops commands for another device without a word
+            let mut locked = clients.lock().unwrap();
+            for (hash, _) in request.changes() {
+                let relay = match hash.split('-').collect::<Vec<_>>().as_slice() {
+                    ["io", device, n] if *device == mac_id => n.parse::<usize>().ok(),
+                    _ => None,
+                };
+                if relay.filter(|r| *r < locked.relays.len()).is_none() {
+                    return error(&format!("unknown relay {:?}", hash));
+                }
+            }
+            locked.pending.insert(reqid.clone(), id);
+            drop(locked);
+            if !forward(request) {
+                clients.lock().unwrap().pending.remove(&reqid);
+                return error("too many pending commands");
+            }
+            None
+        }
+        "set" => match forward(request) {
+            true => Some(reply_value(&reqid, None).to_string()),
+            false => error("too many pending commands"),
+        },
+        cmd => error(&format!("unknown cmd {:?}, expected set, get or subscribe", cmd)),
+    }
+}
+
+async fn serve(stream: UnixStream, clients: Arc<Mutex<Clients>>, mac_id: String, tx: mpsc::Sender<Result<TransportOut, OtaErr>>) {
+    let (read, mut write) = stream.into_split();
+    let (line_tx, mut line_rx) = mpsc::channel::<String>(CLIENT_QUEUE);
+    let id = {
+        let mut clients = clients.lock().unwrap();
+        clients.next_id += 1;
+        let id = clients.next_id;
+        clients.writers.insert(id, line_tx);
+        id
+    };
+    log::info!("socket: client {} connected", id);
+
+    tokio::spawn(async move {
+        while let Some(mut line) = line_rx.recv().await {
+            line.push('\n');
+            if write.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !clients.lock().unwrap().writers.contains_key(&id) {
+            break;
+        }
+        if let Some(reply) = request(&line, id, &clients, &mac_id, &tx) {
+            clients.lock().unwrap().write(id, reply);
+        }
+    }
+    clients.lock().unwrap().remove(id);
+    log::info!("socket: client {} gone", id);
+}
+
+// Newline-delimited JSON on a Unix socket, for boxes without a broker.
+// Requests use the MQTT command shapes; "subscribe" streams relay, button and
+// temperature events.
+pub struct SocketDriver {
+    path: PathBuf,
+    clients: Arc<Mutex<Clients>>,
+    rx: mpsc::Receiver<Result<TransportOut, OtaErr>>,
+    json: JsonDriver,
+    mac_id: String,
+}
+
+impl SocketDriver {
+    pub fn new(path: impl AsRef<Path>, mode: u32, mac_id: &str) -> Result<SocketDriver, OtaErr> {
+        let path = path.as_ref().to_path_buf();
+        let fail = |what: &str, e: std::io::Error| {
+            log::error!("socket {:?}: {}: {}", path, what, e);
+            OtaErr::SocketErr
+        };
+
+        // a socket left behind by a previous run is stale, anything else is not ours
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                log::error!("socket {:?}: exists and is not a socket", path);
+                return Err(OtaErr::SocketErr);
+            }
+            std::fs::remove_file(&path).map_err(|e| fail("remove stale socket", e))?;
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| fail("create directory", e))?;
+        }
+        let listener = UnixListener::bind(&path).map_err(|e| fail("bind", e))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).map_err(|e| fail("chmod", e))?;
+
+        let clients = Arc::new(Mutex::new(Clients::default()));
+        let (tx, rx) = mpsc::channel::<Result<TransportOut, OtaErr>>(16);
+        let accept = clients.clone();
+        let accept_mac = mac_id.to_string();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve(stream, accept.clone(), accept_mac.clone(), tx.clone()));
+                    }
+                    Err(e) => {
+                        log::error!("socket accept: {}", e);
+                        sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        });
+        log::info!("socket: listening on {:?}", path);
+
+        Ok(SocketDriver { path, clients, rx, json: JsonDriver{}, mac_id: mac_id.to_string() })
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for SocketDriver {
+    fn name(&self) -> &'static str {
+        "socket"
+    }
+
+    async fn send(&mut self, data: TransportIn) -> Result<(), OtaErr> {
+        match data {
+            TransportIn::Relays(relays) | TransportIn::Sync { relays, .. } => self.clients.lock().unwrap().relays = relays,
+            TransportIn::RelayStatus { relays, json_init } => {
+                let mut changed = Vec::new();
+                let client = {
+                    let mut clients = self.clients.lock().unwrap();
+                    // delayed and cancelled commands leave the relay as it is
+                    for r in relays.iter().filter(|r| r.result.is_ok()) {
+                        match clients.relays.get_mut(r.relay) {
+                            Some(state) if *state == r.on => {}
+                            Some(state) => {
+                                *state = r.on;
+                                changed.push((r.relay, r.on));
+                            }
+                            None => changed.push((r.relay, r.on)),
+                        }
+                    }
+                    clients.pending.remove(&json_init.reqid)
+                };
+                if let Some(client) = client {
+                    let (mess, _) = self.json.convert(JsonIn::relay_status(json_init, &self.mac_id, relays)).await;
+                    self.clients.lock().unwrap().write(client, mess);
+                }
+                for (relay, on) in changed {
+                    let event = local_event_value("relay", json!({ "relay": relay, "on": on }));
+                    self.clients.lock().unwrap().broadcast(event.to_string());
+                }
+            }
+            TransportIn::Temperature(temp) => {
+                self.clients.lock().unwrap().temperature = Some(temp);
+                let celsius = (temp as f64 * 10.0).round() / 10.0;
+                let event = local_event_value("temperature", json!({ "celsius": celsius }));
+                self.clients.lock().unwrap().broadcast(event.to_string());
+            }
+            TransportIn::ButtonEvent { action } => {
+                let event = local_event_value("button", json!({ "action": action }));
+                self.clients.lock().unwrap().broadcast(event.to_string());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<TransportOut, OtaErr> {
+        match self.rx.recv().await {
+            Some(out) => out,
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("socket {:?}: remove: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::Value;
+    use crate::transport::RelayResult;
+
+    async fn line(lines: &mut tokio::io::Lines<BufReader<tokio::net::unix::OwnedReadHalf>>) -> Value {
+        let line = tokio::time::timeout(Duration::from_secs(1), lines.next_line()).await.unwrap().unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_socket_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run/io.sock");
+        let mut driver = SocketDriver::new(&path, 0o660, "AB12").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+        driver.send(TransportIn::Relays(vec![false, false])).await.unwrap();
+
+        let (read, mut write) = UnixStream::connect(&path).await.unwrap().into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        write.write_all(b"{\"cmd\":\"subscribe\",\"reqid\":\"s1\"}\n{\"cmd\":\"get\",\"reqid\":\"g1\"}\nnot json\n").await.unwrap();
+        assert_eq!(line(&mut lines).await["cmd"], "ack");
+        let status = line(&mut lines).await;
+        assert_eq!(status["reqid"], "g1");
+        assert_eq!(status["objects"][0]["data"][1]["hash"], "io-AB12-1");
+        assert_eq!(line(&mut lines).await["cmd"], "error");
+
+        let set = json!({
+            "cmd": "set",
+            "control_source": {"id": "", "previous_control_reqid": "", "type": "app"},
+            "objects": [{"data": ["io-AB12-1"], "execution": {"params": {"on": true}}}],
+            "reqid": "r1"
+        });
+        write.write_all(format!("{}\n", set).as_bytes()).await.unwrap();
+        let json_init = match driver.recv().await {
+            Ok(TransportOut::ResponseMqttEvent(request)) => request,
+            _ => panic!("expected the relay command"),
+        };
+        driver.send(TransportIn::RelayStatus { relays: vec![RelayResult { relay: 1, on: true, result: Ok(()), timer: None, hash: None }], json_init }).await.unwrap();
+        let status = line(&mut lines).await;
+        assert_eq!(status["reqid"], "r1");
+        assert_eq!(status["objects"][0]["data"][0]["states"]["OnOff"]["on"], true);
+        let event = line(&mut lines).await;
+        assert_eq!(event["objects"][0]["type"], "relay");
+        assert_eq!(event["objects"][0]["data"][0]["relay"], 1);
+
+        let other = set.to_string().replace("io-AB12-1", "io-CD34-1");
+        write.write_all(format!("{}\n", other).as_bytes()).await.unwrap();
+        assert_eq!(line(&mut lines).await["cmd"], "error");
+
+        // a batch is answered with one status, errors only on the relays that failed
+        let batch = set.to_string().replace(r#"["io-AB12-1"]"#, r#"["io-AB12-0","io-AB12-1"]"#).replace("r1", "r2");
+        write.write_all(format!("{}\n", batch).as_bytes()).await.unwrap();
+        let json_init = match driver.recv().await {
+            Ok(TransportOut::ResponseMqttEvent(request)) => request,
+            _ => panic!("expected the relay command"),
+        };
+        let hashes: Vec<&str> = json_init.changes().into_iter().map(|(hash, _)| hash).collect();
+        assert_eq!(hashes, vec!["io-AB12-0", "io-AB12-1"]);
+        let relays = vec![
+            RelayResult { relay: 0, on: true, result: Ok(()), timer: None, hash: None },
+            RelayResult { relay: 1, on: true, result: Err(OtaErr::SetValueErr), timer: None, hash: None },
+        ];
+        driver.send(TransportIn::RelayStatus { relays, json_init }).await.unwrap();
+        let status = line(&mut lines).await;
+        assert_eq!(status["reqid"], "r2");
+        assert_eq!(status["objects"][0]["data"][0], json!({"hash": "io-AB12-0", "states": {"OnOff": {"on": true}}}));
+        assert_eq!(status["objects"][0]["data"][1]["error"], "write_failed");
+        assert_eq!(line(&mut lines).await["objects"][0]["data"][0]["relay"], 0);
+
+        driver.shutdown().await;
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use crate::config::TlsConfig;
+
+// Checks the broker certificate against a fixed name instead of the host we
+// connect to, for brokers reached by IP or through a tunnel.
+struct NameOverride {
+    inner: WebPkiVerifier,
+    name: ServerName,
+}
+
+impl ServerCertVerifier for NameOverride {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(end_entity, intermediates, &self.name, scts, ocsp_response, now)
+    }
+}
+
+fn open(key: &str, path: &str) -> Result<BufReader<File>, String> {
+    File::open(path).map(BufReader::new).map_err(|e| format!("{} = {:?}: {}", key, path, e))
+}
+
+fn certs(key: &str, path: &str) -> Result<Vec<Certificate>, String> {
+    let certs = rustls_pemfile::certs(&mut open(key, path)?).map_err(|e| format!("{} = {:?}: {}", key, path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{} = {:?}: no PEM certificate found", key, path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn private_key(key: &str, path: &str) -> Result<PrivateKey, String> {
+    let mut reader = open(key, path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(|e| format!("{} = {:?}: {}", key, path, e))? {
+            Some(rustls_pemfile::Item::PKCS8Key(der))
+            | Some(rustls_pemfile::Item::RSAKey(der))
+            | Some(rustls_pemfile::Item::ECKey(der)) => return Ok(PrivateKey(der)),
+            Some(_) => continue,
+            None => return Err(format!("{} = {:?}: no PEM private key found", key, path)),
+        }
+    }
+}
+
+fn roots(config: &TlsConfig) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    if config.ca_file.is_empty() {
+        let native = rustls_native_certs::load_native_certs().map_err(|e| format!("mqtt.tls: system certificates: {}", e))?;
+        let der: Vec<Vec<u8>> = native.into_iter().map(|c| c.0).collect();
+        roots.add_parsable_certificates(&der);
+    }
+    else {
+        for cert in certs("mqtt.tls.ca_file", &config.ca_file)? {
+            roots.add(&cert).map_err(|e| format!("mqtt.tls.ca_file = {:?}: {}", config.ca_file, e))?;
+        }
+    }
+    Ok(roots)
+}
+
+// The rustls client config for mqtt.tls; CA, client auth, ALPN and server
+// name are all optional.
+pub fn client_config(config: &TlsConfig) -> Result<Arc<ClientConfig>, String> {
+    let roots = roots(config)?;
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots.clone());
+
+    let mut client = match (config.cert_file.is_empty(), config.key_file.is_empty()) {
+        (true, true) => builder.with_no_client_auth(),
+        (false, false) => {
+            let chain = certs("mqtt.tls.cert_file", &config.cert_file)?;
+            let key = private_key("mqtt.tls.key_file", &config.key_file)?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| format!("mqtt.tls.cert_file / key_file: {}", e))?
+        }
+        _ => return Err("mqtt.tls.cert_file and mqtt.tls.key_file must be set together".to_string()),
+    };
+
+    client.alpn_protocols = config.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+    if !config.server_name.is_empty() {
+        let name = ServerName::try_from(config.server_name.as_str())
+            .map_err(|e| format!("mqtt.tls.server_name = {:?}: {}", config.server_name, e))?;
+        client.dangerous().set_certificate_verifier(Arc::new(NameOverride {
+            inner: WebPkiVerifier::new(roots, None),
+            name,
+        }));
+    }
+    Ok(Arc::new(client))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_client_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let not_pem = dir.path().join("ca.pem");
+        std::fs::write(&not_pem, "not a certificate\n").unwrap();
+
+        let mut config = TlsConfig {
+            enabled: true,
+            alpn: vec!["mqtt".to_string()],
+            server_name: "broker.lumi.local".to_string(),
+            ..TlsConfig::default()
+        };
+        let client = client_config(&config).unwrap();
+        assert_eq!(client.alpn_protocols, vec![b"mqtt".to_vec()]);
+
+        config.ca_file = not_pem.to_string_lossy().to_string();
+        let err = client_config(&config).err().unwrap();
+        assert!(err.starts_with("mqtt.tls.ca_file"), "{}", err);
+
+        config.ca_file = String::new();
+        config.cert_file = "/etc/io-service/client.pem".to_string();
+        let err = client_config(&config).err().unwrap();
+        assert!(err.contains("must be set together"), "{}", err);
+    }
+}